@@ -0,0 +1,36 @@
+//! Input and output shared by the benches.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io::{self, Write};
+
+/// Size of the records, as in the sortbenchmark.org format.
+pub const RECORD_SIZE: usize = 100;
+
+/// Generates `len` bytes of records with pseudo-random content.
+pub fn input(len: usize) -> Vec<u8> {
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        data.extend_from_slice(&seed.to_le_bytes());
+    }
+    data.truncate(len - len % RECORD_SIZE);
+    data
+}
+
+/// Hashes everything written, so outputs can be compared without keeping them.
+pub struct HashWriter(pub DefaultHasher);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
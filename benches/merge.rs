@@ -0,0 +1,114 @@
+//! Compares picking the next record of a K-way merge with a linear scan over all runs (the way
+//! `FileSortHelper::merge` used to do it) and with the loser tree of the sorter, up to K = 1,000
+//! runs merged at once.
+//!
+//! Run with `cargo bench --bench merge`. The input is 64 MiB by default, set `BENCH_MB` to change
+//! it. It's split into K runs sorted in memory, so the merge doesn't wait for any reads.
+
+mod common;
+
+// The tests of the module aren't built without the test harness, leaving their imports unused.
+#[path = "../src/loser_tree.rs"]
+#[allow(unused_imports)]
+mod loser_tree;
+
+use common::{input, HashWriter, RECORD_SIZE};
+use loser_tree::LoserTree;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Length of the keys the records are compared on, as in the sortbenchmark.org format.
+const KEY_LEN: usize = 10;
+
+fn key(record: &[u8]) -> &[u8] {
+    &record[..KEY_LEN]
+}
+
+/// Splits `input` into `runs` runs of records sorted on their keys.
+fn runs(input: &[u8], runs: usize) -> Vec<Vec<&[u8]>> {
+    let records: Vec<&[u8]> = input.chunks_exact(RECORD_SIZE).collect();
+    (0..runs)
+        .map(|i| {
+            let (start, end) = (i * records.len() / runs, (i + 1) * records.len() / runs);
+            let mut run = records[start..end].to_vec();
+            run.sort_by(|a, b| key(a).cmp(key(b)));
+            run
+        })
+        .collect()
+}
+
+fn merge_linear(runs: &[Vec<&[u8]>], out: &mut impl Write) {
+    let mut pos = vec![0; runs.len()];
+    loop {
+        let mut min_ind = None;
+        let mut min: &[u8] = &[];
+        for (i, run) in runs.iter().enumerate() {
+            if let Some(&record) = run.get(pos[i]) {
+                if min_ind.is_none() || key(record) < key(min) {
+                    min = record;
+                    min_ind = Some(i);
+                }
+            }
+        }
+        match min_ind {
+            Some(i) => {
+                out.write_all(min).unwrap();
+                pos[i] += 1;
+            }
+            None => return,
+        }
+    }
+}
+
+fn merge_loser_tree(runs: &[Vec<&[u8]>], out: &mut impl Write) {
+    let mut pos = vec![0; runs.len()];
+    let less = |pos: &[usize], a: usize, b: usize| match (runs[a].get(pos[a]), runs[b].get(pos[b]))
+    {
+        (Some(x), Some(y)) => (key(x), a) < (key(y), b),
+        (x, _) => x.is_some(),
+    };
+    let mut tree = LoserTree::new(runs.len(), |a, b| less(&pos, a, b));
+    while let Some(&record) = runs[tree.winner()].get(pos[tree.winner()]) {
+        out.write_all(record).unwrap();
+        pos[tree.winner()] += 1;
+        tree.replay(|a, b| less(&pos, a, b));
+    }
+}
+
+/// Times `merge` on `runs`, and prints how long it takes.
+fn bench<F>(name: &str, runs: &[Vec<&[u8]>], merge: F) -> u64
+where
+    F: Fn(&[Vec<&[u8]>], &mut HashWriter),
+{
+    let records: usize = runs.iter().map(Vec::len).sum();
+    let mut best = Duration::MAX;
+    let mut hash = 0;
+    for _ in 0..3 {
+        let mut out = HashWriter(DefaultHasher::new());
+        let start = Instant::now();
+        merge(runs, &mut out);
+        best = best.min(start.elapsed());
+        hash = out.0.finish();
+    }
+    println!(
+        "{:<12} K = {:>5}: {:>10.2?} ({:.1} ns per record)",
+        name,
+        runs.len(),
+        best,
+        best.as_nanos() as f64 / records as f64
+    );
+    hash
+}
+
+fn main() {
+    let mb: usize = std::env::var("BENCH_MB").map_or(64, |mb| mb.parse().unwrap());
+    let input = input(mb << 20);
+    for &k in &[10, 100, 1_000] {
+        let runs = runs(&input, k);
+        let linear = bench("linear scan", &runs, merge_linear);
+        let tree = bench("loser tree", &runs, merge_loser_tree);
+        assert_eq!(linear, tree);
+    }
+}
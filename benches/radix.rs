@@ -0,0 +1,59 @@
+//! Compares sorting fixed size records with the radix sort, which the sorter picks for keys
+//! compared byte by byte, and with the comparison sort, which it uses for custom comparators.
+//!
+//! Run with `cargo bench --bench radix`. The input is 1 GiB by default, which takes about 3 GiB of
+//! memory, set `BENCH_MB` to sort less. The memory budget covers the whole input along with the
+//! index of its records, so it is sorted as a single run.
+
+mod common;
+
+use big_file_sort::{RecordFormat, Sorter};
+use common::{input, HashWriter, RECORD_SIZE};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::time::{Duration, Instant};
+
+fn bench(name: &str, input: &[u8], sorter: impl Fn() -> Sorter) -> u64 {
+    let mut best = Duration::MAX;
+    let mut hash = 0;
+    for _ in 0..3 {
+        let mut out = HashWriter(DefaultHasher::new());
+        let start = Instant::now();
+        let report = sorter().run_stream(input, &mut out).unwrap();
+        best = best.min(start.elapsed());
+        assert_eq!(report.runs, 1);
+        hash = out.0.finish();
+    }
+    println!(
+        "{:<24} {:.0} MiB: {:>10.2?} ({:.1} ns per record)",
+        name,
+        input.len() as f64 / f64::from(1 << 20),
+        best,
+        best.as_nanos() as f64 / (input.len() / RECORD_SIZE) as f64
+    );
+    hash
+}
+
+fn main() {
+    let mb: usize = std::env::var("BENCH_MB").map_or(1024, |mb| mb.parse().unwrap());
+    let input = input(mb << 20);
+    // 10-byte keys at the start as in the sortbenchmark.org format, and 8-byte keys inside the
+    // records, e.g. big-endian integers.
+    for &(key_offset, key_len) in &[(0, 10), (20, 8)] {
+        let sorter = || {
+            Sorter::default()
+                .memory(2 * input.len() as u64)
+                .parallelism(1)
+                .format(RecordFormat::Fixed {
+                    size: RECORD_SIZE,
+                    key_offset,
+                    key_len,
+                })
+        };
+        let radix = bench(&format!("radix, {}-byte key", key_len), &input, sorter);
+        let comparison = bench(&format!("comparison, {}-byte key", key_len), &input, || {
+            sorter().comparator(|a, b| a.cmp(b))
+        });
+        assert_eq!(radix, comparison);
+    }
+}
@@ -0,0 +1,54 @@
+//! Checking whether the input is sorted already, without sorting it.
+
+use crate::record::Records;
+use crate::runs::ChunkReader;
+use crate::SortError;
+use std::io::Read;
+
+/// The first record out of order found by [`Sorter::check`](crate::Sorter::check).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Disorder {
+    /// Position of the record in the input, in bytes.
+    pub offset: u64,
+    /// Number of the record counted from 1, i.e. the line number of lines.
+    pub line: u64,
+    /// The record as it is in the input, including its terminator if any.
+    pub record: Vec<u8>,
+}
+
+/// Reads the input and returns the first record going before the previous one, or equal to it
+/// with `unique` set.
+pub(crate) fn check_order<F: Records, R: Read>(
+    reader: &mut ChunkReader<F, R>,
+    unique: bool,
+) -> Result<Option<Disorder>, SortError> {
+    let records = reader.records;
+    let mut chunk = Vec::new();
+    // The last record of the previous chunk.
+    let mut last: Option<Vec<u8>> = None;
+    let (mut offset, mut line) = (0, 0);
+    while let Some(len) = reader.next_chunk(&mut chunk)? {
+        let mut prev = last.as_deref();
+        let mut pos = 0;
+        while pos < len {
+            // The last line of the input may lack its terminator.
+            let record_len = records.input_len(&chunk[pos..len]).unwrap_or(len - pos);
+            let record = &chunk[pos..pos + record_len];
+            line += 1;
+            let ord = prev.map(|prev| records.cmp_input(prev, record));
+            if ord.is_some_and(|ord| ord.is_gt() || unique && ord.is_eq()) {
+                return Ok(Some(Disorder {
+                    offset: offset + pos as u64,
+                    line,
+                    record: record.to_vec(),
+                }));
+            }
+            prev = Some(record);
+            pos += record_len;
+        }
+        last = prev.map(<[u8]>::to_vec);
+        offset += len as u64;
+        chunk.drain(..len);
+    }
+    Ok(None)
+}
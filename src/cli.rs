@@ -0,0 +1,599 @@
+use big_file_sort::{
+    Collation, Keep, KeySpec, RecordFormat, RunStrategy, SortError, Sorter, Strength,
+    DEFAULT_MEMORY,
+};
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+pub(crate) const USAGE: &str = "\
+Usage: big-file-sort [OPTION]... [INPUT]
+
+Sorts INPUT, or the standard input when INPUT is absent or `-`, using a bounded amount of memory.
+
+Options:
+  -o, --output=FILE              write the result to FILE instead of the standard output
+  -S, --buffer-size=SIZE         use SIZE bytes of memory; accepts K, M, G and T suffixes
+                                 (powers of 1024), defaults to 64M
+  -T, --temporary-directory=DIR  store temporary files in DIR; may be repeated to spread
+                                 them over several disks
+      --records=MODE             split the input into `lines` (the default), `bytes`, or
+                                 records of SIZE bytes with `fixed:SIZE`; those are compared
+                                 on LEN bytes at OFFSET with `fixed:SIZE:OFFSET:LEN`
+  -k, --key=KEYDEF               sort lines on a key; may be repeated, see below
+  -t, --field-separator=SEP      separate the fields of keys with the byte SEP instead of
+                                 the transitions from non-blanks to blanks
+  -b, --ignore-leading-blanks    skip leading blanks of the fields of keys
+  -d, --dictionary-order         compare only blanks and alphanumeric characters
+  -f, --ignore-case              fold lowercase letters to uppercase
+  -i, --ignore-nonprinting       compare only printable characters
+  -n, --numeric-sort             compare decimal numbers, such as `-12.5`
+  -g, --general-numeric-sort     compare floating point numbers, such as `1.5e3` or `inf`
+  -h, --human-numeric-sort       compare numbers with SI suffixes, such as `2K` or `1G`
+  -V, --version-sort             compare versions and file names, such as `v1.10.2`
+      --collate=STRENGTH         sort lines in the Unicode collation order, with accented
+                                 letters next to their base letters; STRENGTH is `primary`
+                                 (letters only), `secondary` (and accents) or `tertiary`
+                                 (and case); can't be combined with keys
+  -r, --reverse                  sort in descending order
+  -s, --stable                   keep lines with equal keys in the input order, instead of
+                                 comparing them as a whole
+  -u, --unique                   output only one of equal records
+      --count                    output each distinct line once, after the number of its
+                                 occurrences, like `sort | uniq -c`; implies `-u`
+      --keep=WHICH               keep the `first` (the default) or the `last` of equal
+                                 records in the input order with `-u` and `--count`
+      --runs=STRATEGY            generate the sorted runs from `chunks` of SIZE (the default),
+                                 or by `replacement` selection, which makes runs twice as
+                                 long on average and a single one of sorted input; the
+                                 latter can't be combined with `--parallel`
+      --parallel=N               sort with N threads, defaults to 1
+  -c, --check[=diagnose-first]   check whether the input is sorted instead of sorting it, and
+                                 report the first record out of order with its line number
+                                 and byte offset; with `-u`, equal records are out of order
+  -C, --check=quiet              check like `-c`, but report nothing; `silent` works as well
+      --help                     print this help and exit
+      --version                  print the version and exit
+
+KEYDEF is FIELD[.CHAR][OPTS][,FIELD[.CHAR][OPTS]], with fields and characters numbered from 1.
+Without the second position the key lasts till the end of the line. OPTS are any of `bdfinghVr`,
+standing for the options above, and apply to the key only. The options given separately apply to
+the keys without OPTS, or to the whole line when there are no keys. Lines with equal keys are
+compared as a whole, in descending order with `-r`, unless `-s` is given.
+
+Exit status: 0 on success, 1 when the input is out of order with `-c` or `-C`, 2 on invalid
+arguments, 3 on I/O errors, 4 on malformed input, 5 when a limit is exceeded.";
+
+/// What the binary is asked to do.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Command {
+    Sort(Args),
+    Help,
+    Version,
+}
+
+/// How the order of the input is checked instead of sorting it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Check {
+    /// Reports the first record out of order.
+    Diagnose,
+    /// Only tells by the exit status.
+    Quiet,
+}
+
+/// Arguments of sorting.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Args {
+    /// Input file, `None` for the standard input.
+    pub(crate) input: Option<PathBuf>,
+    /// Output file, `None` for the standard output.
+    pub(crate) output: Option<PathBuf>,
+    pub(crate) memory: u64,
+    pub(crate) temp_dirs: Vec<PathBuf>,
+    pub(crate) format: RecordFormat,
+    pub(crate) keys: Vec<KeySpec>,
+    pub(crate) separator: Option<u8>,
+    pub(crate) collation: Option<Strength>,
+    pub(crate) reverse: bool,
+    pub(crate) stable: bool,
+    pub(crate) unique: bool,
+    pub(crate) keep: Keep,
+    pub(crate) count: bool,
+    pub(crate) run_strategy: RunStrategy,
+    pub(crate) parallelism: usize,
+    /// Checks the order of the input rather than sorting it.
+    pub(crate) check: Option<Check>,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            input: None,
+            output: None,
+            memory: DEFAULT_MEMORY,
+            temp_dirs: Vec::new(),
+            format: RecordFormat::Lines,
+            keys: Vec::new(),
+            separator: None,
+            collation: None,
+            reverse: false,
+            stable: false,
+            unique: false,
+            keep: Keep::First,
+            count: false,
+            run_strategy: RunStrategy::Chunks,
+            parallelism: 1,
+            check: None,
+        }
+    }
+}
+
+impl Args {
+    /// Returns a sorter configured by the arguments. The output path is left for the caller.
+    pub(crate) fn sorter(&self) -> Sorter {
+        let mut sorter = Sorter::new(self.input.clone().unwrap_or_default())
+            .memory(self.memory)
+            .temp_dirs(self.temp_dirs.iter().cloned())
+            .format(self.format)
+            .reverse(self.reverse)
+            .stable(self.stable)
+            .unique(self.unique)
+            .keep(self.keep)
+            .count(self.count)
+            .run_strategy(self.run_strategy)
+            .parallelism(self.parallelism);
+        for key in &self.keys {
+            sorter = sorter.key(key.clone());
+        }
+        if let Some(separator) = self.separator {
+            sorter = sorter.field_separator(separator);
+        }
+        if let Some(strength) = self.collation {
+            sorter = sorter.collation(Collation::new(strength));
+        }
+        sorter
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Opt {
+    Output,
+    BufferSize,
+    TempDir,
+    Records,
+    Key,
+    FieldSeparator,
+    /// An option of comparing the keys, given by its letter in key definitions.
+    KeyOption(char),
+    Collate,
+    Reverse,
+    Stable,
+    Unique,
+    Keep,
+    Count,
+    Runs,
+    Parallel,
+    Check(Check),
+    Help,
+    Version,
+}
+
+impl Opt {
+    fn from_long(name: &str) -> Option<Opt> {
+        Some(match name {
+            "output" => Opt::Output,
+            "buffer-size" => Opt::BufferSize,
+            "temporary-directory" => Opt::TempDir,
+            "records" => Opt::Records,
+            "key" => Opt::Key,
+            "field-separator" => Opt::FieldSeparator,
+            "ignore-leading-blanks" => Opt::KeyOption('b'),
+            "dictionary-order" => Opt::KeyOption('d'),
+            "ignore-case" => Opt::KeyOption('f'),
+            "ignore-nonprinting" => Opt::KeyOption('i'),
+            "numeric-sort" => Opt::KeyOption('n'),
+            "general-numeric-sort" => Opt::KeyOption('g'),
+            "human-numeric-sort" => Opt::KeyOption('h'),
+            "version-sort" => Opt::KeyOption('V'),
+            "collate" => Opt::Collate,
+            "reverse" => Opt::Reverse,
+            "stable" => Opt::Stable,
+            "unique" => Opt::Unique,
+            "keep" => Opt::Keep,
+            "count" => Opt::Count,
+            "runs" => Opt::Runs,
+            "parallel" => Opt::Parallel,
+            "check" => Opt::Check(Check::Diagnose),
+            "help" => Opt::Help,
+            "version" => Opt::Version,
+            _ => return None,
+        })
+    }
+
+    fn from_short(name: char) -> Option<Opt> {
+        Some(match name {
+            'o' => Opt::Output,
+            'S' => Opt::BufferSize,
+            'T' => Opt::TempDir,
+            'k' => Opt::Key,
+            't' => Opt::FieldSeparator,
+            'b' | 'd' | 'f' | 'i' | 'n' | 'g' | 'h' | 'V' => Opt::KeyOption(name),
+            'r' => Opt::Reverse,
+            's' => Opt::Stable,
+            'u' => Opt::Unique,
+            'c' => Opt::Check(Check::Diagnose),
+            'C' => Opt::Check(Check::Quiet),
+            _ => return None,
+        })
+    }
+
+    fn takes_value(self) -> bool {
+        matches!(
+            self,
+            Opt::Output
+                | Opt::BufferSize
+                | Opt::TempDir
+                | Opt::Records
+                | Opt::Key
+                | Opt::FieldSeparator
+                | Opt::Collate
+                | Opt::Keep
+                | Opt::Runs
+                | Opt::Parallel
+        )
+    }
+
+    /// Tells whether the long option may be given a value, without taking the next argument.
+    fn takes_optional_value(self) -> bool {
+        matches!(self, Opt::Check(_))
+    }
+}
+
+/// Parses the command line arguments, without the program name.
+pub(crate) fn parse<I: IntoIterator<Item = OsString>>(args: I) -> Result<Command, String> {
+    let mut args = args.into_iter();
+    let mut parsed = Args::default();
+    let mut keys = KeyDefs::default();
+    let mut inputs = Vec::new();
+    let mut only_inputs = false;
+    while let Some(arg) = args.next() {
+        let arg_str = match arg.to_str() {
+            Some(s) if !only_inputs && s.starts_with('-') && s != "-" => s.to_owned(),
+            _ => {
+                inputs.push(arg);
+                continue;
+            }
+        };
+        if arg_str == "--" {
+            only_inputs = true;
+        } else if let Some(long) = arg_str.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(OsString::from(value))),
+                None => (long, None),
+            };
+            let opt = Opt::from_long(name).ok_or_else(|| format!("unknown option `--{}`", name))?;
+            let value = match (opt.takes_value(), value) {
+                (true, Some(value)) => Some(value),
+                (true, None) => Some(
+                    args.next()
+                        .ok_or_else(|| format!("option `--{}` requires a value", name))?,
+                ),
+                (false, Some(value)) if opt.takes_optional_value() => Some(value),
+                (false, Some(_)) => return Err(format!("option `--{}` takes no value", name)),
+                (false, None) => None,
+            };
+            if let Some(command) = apply(&mut parsed, &mut keys, opt, value)? {
+                return Ok(command);
+            }
+        } else {
+            // A cluster of short options, the last of which may take a value, e.g. `-ruS1G`.
+            let shorts = &arg_str[1..];
+            for (i, name) in shorts.char_indices() {
+                let opt =
+                    Opt::from_short(name).ok_or_else(|| format!("unknown option `-{}`", name))?;
+                let value = if !opt.takes_value() {
+                    None
+                } else if i + name.len_utf8() < shorts.len() {
+                    Some(OsString::from(&shorts[i + name.len_utf8()..]))
+                } else {
+                    Some(
+                        args.next()
+                            .ok_or_else(|| format!("option `-{}` requires a value", name))?,
+                    )
+                };
+                let has_value = value.is_some();
+                if let Some(command) = apply(&mut parsed, &mut keys, opt, value)? {
+                    return Ok(command);
+                }
+                if has_value {
+                    break;
+                }
+            }
+        }
+    }
+    if inputs.len() > 1 {
+        return Err("only one input file can be sorted".into());
+    }
+    if parsed.check.is_some() && parsed.output.is_some() {
+        return Err("checking can't be combined with an output file".into());
+    }
+    parsed.input = inputs.pop().filter(|input| input != "-").map(PathBuf::from);
+    parsed.keys = keys.resolve(parsed.reverse)?;
+    Ok(Command::Sort(parsed))
+}
+
+/// Key definitions and the separately given key options.
+#[derive(Default)]
+struct KeyDefs {
+    defs: Vec<String>,
+    options: String,
+}
+
+impl KeyDefs {
+    /// Parses the key definitions, adding the separate options to the ones without their own, `r`
+    /// among them when `reverse` is set. Keys with their own options are left as they are.
+    fn resolve(self, reverse: bool) -> Result<Vec<KeySpec>, String> {
+        let mut defs = self.defs;
+        if defs.is_empty() && !self.options.is_empty() {
+            defs.push("1".into());
+        }
+        let mut options = self.options;
+        if reverse {
+            options.push('r');
+        }
+        defs.iter()
+            .map(|def| {
+                let def = if def.contains(|c: char| c.is_ascii_alphabetic()) {
+                    def.clone()
+                } else if let Some((start, end)) = def.split_once(',') {
+                    format!("{}{},{}{}", start, options, end, options)
+                } else {
+                    format!("{}{}", def, options)
+                };
+                def.parse().map_err(|e: SortError| e.to_string())
+            })
+            .collect()
+    }
+}
+
+/// Applies an option to the arguments. Returns the command to run right away, if any.
+fn apply(
+    args: &mut Args,
+    keys: &mut KeyDefs,
+    opt: Opt,
+    value: Option<OsString>,
+) -> Result<Option<Command>, String> {
+    let value = value.unwrap_or_default();
+    let text = || {
+        value
+            .to_str()
+            .ok_or_else(|| format!("invalid value `{}`", value.to_string_lossy()))
+    };
+    match opt {
+        Opt::Output => args.output = Some(value.clone()).filter(|o| o != "-").map(PathBuf::from),
+        Opt::BufferSize => args.memory = parse_size(text()?)?,
+        Opt::TempDir => args.temp_dirs.push(PathBuf::from(&value)),
+        Opt::Records => {
+            args.format = parse_records(text()?)?;
+        }
+        Opt::Key => keys.defs.push(text()?.to_owned()),
+        Opt::FieldSeparator => {
+            args.separator = Some(match text()?.as_bytes() {
+                [separator] => *separator,
+                b"\\0" => 0,
+                _ => return Err("the field separator must be a single byte".into()),
+            })
+        }
+        Opt::KeyOption(option) => keys.options.push(option),
+        Opt::Collate => {
+            args.collation = Some(match text()? {
+                "primary" => Strength::Primary,
+                "secondary" => Strength::Secondary,
+                "tertiary" => Strength::Tertiary,
+                strength => return Err(format!("unknown collation strength `{}`", strength)),
+            })
+        }
+        Opt::Reverse => args.reverse = true,
+        Opt::Stable => args.stable = true,
+        Opt::Unique => args.unique = true,
+        Opt::Count => args.count = true,
+        Opt::Keep => {
+            args.keep = match text()? {
+                "first" => Keep::First,
+                "last" => Keep::Last,
+                keep => return Err(format!("unknown duplicate to keep `{}`", keep)),
+            }
+        }
+        Opt::Runs => {
+            args.run_strategy = match text()? {
+                "chunks" => RunStrategy::Chunks,
+                "replacement" => RunStrategy::ReplacementSelection,
+                strategy => return Err(format!("unknown run strategy `{}`", strategy)),
+            }
+        }
+        Opt::Parallel => {
+            args.parallelism = text()?
+                .parse()
+                .map_err(|_| format!("invalid number of threads `{}`", value.to_string_lossy()))?
+        }
+        Opt::Check(check) => {
+            args.check = Some(match text()? {
+                "" => check,
+                "diagnose-first" => Check::Diagnose,
+                "quiet" | "silent" => Check::Quiet,
+                check => return Err(format!("unknown check mode `{}`", check)),
+            })
+        }
+        Opt::Help => return Ok(Some(Command::Help)),
+        Opt::Version => return Ok(Some(Command::Version)),
+    }
+    Ok(None)
+}
+
+/// Parses a record mode: `lines`, `bytes`, `fixed:SIZE` or `fixed:SIZE:OFFSET:LEN`.
+fn parse_records(mode: &str) -> Result<RecordFormat, String> {
+    let invalid = || format!("unknown record mode `{}`", mode);
+    let fixed = match mode {
+        "lines" => return Ok(RecordFormat::Lines),
+        "bytes" => return Ok(RecordFormat::Bytes),
+        _ => mode.strip_prefix("fixed:").ok_or_else(invalid)?,
+    };
+    let numbers = fixed
+        .split(':')
+        .map(str::parse)
+        .collect::<Result<Vec<usize>, _>>()
+        .map_err(|_| invalid())?;
+    match numbers[..] {
+        [size] => Ok(RecordFormat::Fixed {
+            size,
+            key_offset: 0,
+            key_len: size,
+        }),
+        [size, key_offset, key_len] => Ok(RecordFormat::Fixed {
+            size,
+            key_offset,
+            key_len,
+        }),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses a size in bytes with an optional binary suffix, e.g. `512M` or `4G`.
+pub(crate) fn parse_size(size: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid size `{}`", size);
+    let (digits, shift) = match size.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let shift = match c.to_ascii_uppercase() {
+                'B' => 0,
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(invalid()),
+            };
+            (&size[..i], shift)
+        }
+        _ => (size, 0),
+    };
+    let number: u64 = digits.parse().map_err(|_| invalid())?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size `{}` is too large", size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn should_parse_sizes() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("4g"), Ok(4 << 30));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("12X").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn should_parse_arguments() {
+        let expected = Args {
+            input: Some("in.txt".into()),
+            output: Some("out.txt".into()),
+            memory: 1 << 30,
+            temp_dirs: vec!["/a".into(), "/b".into()],
+            format: RecordFormat::Bytes,
+            keys: vec!["2r,2r".parse().unwrap(), "1f".parse().unwrap()],
+            separator: Some(b':'),
+            collation: Some(Strength::Secondary),
+            reverse: true,
+            stable: true,
+            unique: true,
+            keep: Keep::Last,
+            count: true,
+            run_strategy: RunStrategy::ReplacementSelection,
+            parallelism: 4,
+            check: None,
+        };
+        let args = [
+            "-rsuS1G",
+            "-T/a",
+            "--temporary-directory",
+            "/b",
+            "--records=bytes",
+            "-k2,2",
+            "--key=1f",
+            "-t:",
+            "--collate=secondary",
+            "--parallel",
+            "4",
+            "--keep=last",
+            "--count",
+            "--runs=replacement",
+            "-o",
+            "out.txt",
+            "in.txt",
+        ];
+        assert_eq!(parse_args(&args), Ok(Command::Sort(expected)));
+        assert_eq!(
+            parse_args(&["-", "-o-"]),
+            Ok(Command::Sort(Args::default()))
+        );
+        assert_eq!(parse_args(&["-r", "--help"]), Ok(Command::Help));
+        assert!(parse_args(&["--records=words"]).is_err());
+        assert!(parse_args(&["--records=fixed:1:2"]).is_err());
+        match parse_args(&["--records=fixed:100:0:10"]) {
+            Ok(Command::Sort(args)) => assert_eq!(
+                args.format,
+                RecordFormat::Fixed {
+                    size: 100,
+                    key_offset: 0,
+                    key_len: 10
+                }
+            ),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(parse_args(&["-x"]).is_err());
+        assert!(parse_args(&["--collate=quaternary"]).is_err());
+        assert!(parse_args(&["--keep=middle"]).is_err());
+        assert!(parse_args(&["--runs=heap"]).is_err());
+        let check = |args: &[&str]| match parse_args(args) {
+            Ok(Command::Sort(args)) => args.check,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        assert_eq!(check(&["-c"]), Some(Check::Diagnose));
+        assert_eq!(check(&["-uC"]), Some(Check::Quiet));
+        assert_eq!(check(&["--check"]), Some(Check::Diagnose));
+        assert_eq!(check(&["--check=silent"]), Some(Check::Quiet));
+        assert!(parse_args(&["--check=loud"]).is_err());
+        assert!(parse_args(&["-c", "-o", "out.txt"]).is_err());
+        assert!(parse_args(&["-t", "::"]).is_err());
+        assert!(parse_args(&["-k", "0"]).is_err());
+        assert!(parse_args(&["-ng"]).is_err());
+        assert!(parse_args(&["-o"]).is_err());
+        assert!(parse_args(&["a", "b"]).is_err());
+    }
+
+    #[test]
+    fn should_apply_key_options() {
+        let keys = |args: &[&str]| match parse_args(args) {
+            Ok(Command::Sort(args)) => args.keys,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        let parse =
+            |defs: &[&str]| -> Vec<KeySpec> { defs.iter().map(|d| d.parse().unwrap()).collect() };
+        assert_eq!(keys(&["-h"]), parse(&["1h"]));
+        assert_eq!(keys(&["-n", "-k2,2", "-k3f"]), parse(&["2n,2n", "3f"]));
+        assert_eq!(keys(&["-k2.3", "-b"]), parse(&["2.3b"]));
+        // Keys with their own options ignore `-r`.
+        assert_eq!(
+            keys(&["-r", "-k1,1", "-k2nr", "-k3n"]),
+            parse(&["1r,1r", "2nr", "3n"])
+        );
+    }
+}
@@ -0,0 +1,394 @@
+//! Collation of Unicode text after the Unicode Collation Algorithm: texts are compared on their
+//! base letters first, then on their accents, and then on their case.
+
+use crate::SortError;
+use std::collections::HashMap;
+
+/// Differences between texts a [`Collation`] takes into account.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Strength {
+    /// Base letters only, so `a`, `A` and `á` are equal.
+    Primary,
+    /// Base letters and accents, so `a` and `A` are equal, and `á` follows them.
+    Secondary,
+    /// Base letters, accents and case, so `a` goes before `A`, and both of them before `á`.
+    #[default]
+    Tertiary,
+}
+
+/// Order of Unicode text in which accented letters follow their base letters, instead of going
+/// after `z` as they do byte by byte.
+///
+/// Every character weighs a base weight, an accent weight and a case weight, and texts are
+/// compared on the base weights of all their characters first, then on the accent weights, and
+/// then on the case weights, as far as the [`Strength`] goes. Spaces go first, followed by
+/// punctuation and symbols, digits, and letters in the order of their lowercase code points.
+/// Control characters are ignored. The accented letters of the Latin-1 Supplement and Latin
+/// Extended-A blocks, as well as letters followed by combining accents in any script, weigh as
+/// their base letters with an accent, and ligatures such as `æ` or `ß` as the letters they are
+/// made of. The table can be tailored for a language:
+///
+/// ```
+/// use big_file_sort::{Collation, Strength};
+///
+/// // Swedish `å`, `ä` and `ö` are letters of their own, following `z`.
+/// let swedish = Collation::new(Strength::Tertiary)
+///     .letter_after('å', 'z')?
+///     .letter_after('ä', 'å')?
+///     .letter_after('ö', 'ä')?;
+/// // German phone books sort `ä` as `ae`.
+/// let phone_book = Collation::new(Strength::Secondary).expand('ä', "ae");
+/// # Ok::<(), big_file_sort::SortError>(())
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Collation {
+    strength: Strength,
+    /// Tailored weights of lowercase characters.
+    tailoring: HashMap<char, Tailoring>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Tailoring {
+    /// Weighs as the text.
+    Expansion(String),
+    /// Weighs as a base letter of the given base weight.
+    Letter(u32),
+}
+
+/// Weights of a character, or of an accent when the base weight is zero.
+struct Element {
+    base: u32,
+    accent: u8,
+    case: u8,
+}
+
+/// Accent weight of letters without an accent.
+const NO_ACCENT: u8 = 1;
+
+/// Accented and composed lowercase Latin letters: the letters they weigh as and their accent, given
+/// by the combining character.
+const LATIN: &[(char, &str, Option<char>)] = &[
+    ('ß', "ss", None),
+    ('à', "a", Some('\u{300}')),
+    ('á', "a", Some('\u{301}')),
+    ('â', "a", Some('\u{302}')),
+    ('ã', "a", Some('\u{303}')),
+    ('ä', "a", Some('\u{308}')),
+    ('å', "a", Some('\u{30a}')),
+    ('æ', "ae", None),
+    ('ç', "c", Some('\u{327}')),
+    ('è', "e", Some('\u{300}')),
+    ('é', "e", Some('\u{301}')),
+    ('ê', "e", Some('\u{302}')),
+    ('ë', "e", Some('\u{308}')),
+    ('ì', "i", Some('\u{300}')),
+    ('í', "i", Some('\u{301}')),
+    ('î', "i", Some('\u{302}')),
+    ('ï', "i", Some('\u{308}')),
+    ('ð', "d", Some('\u{335}')),
+    ('ñ', "n", Some('\u{303}')),
+    ('ò', "o", Some('\u{300}')),
+    ('ó', "o", Some('\u{301}')),
+    ('ô', "o", Some('\u{302}')),
+    ('õ', "o", Some('\u{303}')),
+    ('ö', "o", Some('\u{308}')),
+    ('ø', "o", Some('\u{338}')),
+    ('ù', "u", Some('\u{300}')),
+    ('ú', "u", Some('\u{301}')),
+    ('û', "u", Some('\u{302}')),
+    ('ü', "u", Some('\u{308}')),
+    ('ý', "y", Some('\u{301}')),
+    ('þ', "th", None),
+    ('ÿ', "y", Some('\u{308}')),
+    ('ā', "a", Some('\u{304}')),
+    ('ă', "a", Some('\u{306}')),
+    ('ą', "a", Some('\u{328}')),
+    ('ć', "c", Some('\u{301}')),
+    ('ĉ', "c", Some('\u{302}')),
+    ('ċ', "c", Some('\u{307}')),
+    ('č', "c", Some('\u{30c}')),
+    ('ď', "d", Some('\u{30c}')),
+    ('đ', "d", Some('\u{335}')),
+    ('ē', "e", Some('\u{304}')),
+    ('ĕ', "e", Some('\u{306}')),
+    ('ė', "e", Some('\u{307}')),
+    ('ę', "e", Some('\u{328}')),
+    ('ě', "e", Some('\u{30c}')),
+    ('ĝ', "g", Some('\u{302}')),
+    ('ğ', "g", Some('\u{306}')),
+    ('ġ', "g", Some('\u{307}')),
+    ('ģ', "g", Some('\u{327}')),
+    ('ĥ', "h", Some('\u{302}')),
+    ('ħ', "h", Some('\u{335}')),
+    ('ĩ', "i", Some('\u{303}')),
+    ('ī', "i", Some('\u{304}')),
+    ('ĭ', "i", Some('\u{306}')),
+    ('į', "i", Some('\u{328}')),
+    ('ĳ', "ij", None),
+    ('ĵ', "j", Some('\u{302}')),
+    ('ķ', "k", Some('\u{327}')),
+    ('ĺ', "l", Some('\u{301}')),
+    ('ļ', "l", Some('\u{327}')),
+    ('ľ', "l", Some('\u{30c}')),
+    ('ŀ', "l", Some('\u{307}')),
+    ('ł', "l", Some('\u{335}')),
+    ('ń', "n", Some('\u{301}')),
+    ('ņ', "n", Some('\u{327}')),
+    ('ň', "n", Some('\u{30c}')),
+    ('ō', "o", Some('\u{304}')),
+    ('ŏ', "o", Some('\u{306}')),
+    ('ő', "o", Some('\u{30b}')),
+    ('œ', "oe", None),
+    ('ŕ', "r", Some('\u{301}')),
+    ('ŗ', "r", Some('\u{327}')),
+    ('ř', "r", Some('\u{30c}')),
+    ('ś', "s", Some('\u{301}')),
+    ('ŝ', "s", Some('\u{302}')),
+    ('ş', "s", Some('\u{327}')),
+    ('š', "s", Some('\u{30c}')),
+    ('ţ', "t", Some('\u{327}')),
+    ('ť', "t", Some('\u{30c}')),
+    ('ŧ', "t", Some('\u{335}')),
+    ('ũ', "u", Some('\u{303}')),
+    ('ū', "u", Some('\u{304}')),
+    ('ŭ', "u", Some('\u{306}')),
+    ('ů', "u", Some('\u{30a}')),
+    ('ű', "u", Some('\u{30b}')),
+    ('ų', "u", Some('\u{328}')),
+    ('ŵ', "w", Some('\u{302}')),
+    ('ŷ', "y", Some('\u{302}')),
+    ('ź', "z", Some('\u{301}')),
+    ('ż', "z", Some('\u{307}')),
+    ('ž', "z", Some('\u{30c}')),
+    ('ſ', "s", None),
+];
+
+/// Returns the accent weight of a combining accent.
+fn accent(c: char) -> Option<u8> {
+    match c {
+        '\u{300}'..='\u{36f}' => Some(NO_ACCENT + 1 + (c as u32 - 0x300) as u8),
+        _ => None,
+    }
+}
+
+/// Returns the only lowercase character of `c`, or `c` itself.
+fn lowercase(c: char) -> char {
+    let mut lower = c.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(lower), None) => lower,
+        _ => c,
+    }
+}
+
+/// Returns the base weight of a character which isn't tailored. The lower 4 bits are left for the
+/// letters tailored after it.
+fn base(c: char) -> u32 {
+    let group = if c.is_whitespace() {
+        1
+    } else if c.is_numeric() {
+        3
+    } else if c.is_alphabetic() {
+        4
+    } else {
+        2
+    };
+    group << 25 | (c as u32) << 4
+}
+
+impl Collation {
+    /// Creates a collation of the given strength with the built-in table.
+    pub fn new(strength: Strength) -> Self {
+        Collation {
+            strength,
+            tailoring: HashMap::new(),
+        }
+    }
+
+    /// Sorts `c`, in either case, as if it were `text`, e.g. `ä` as `ae`.
+    pub fn expand(mut self, c: char, text: &str) -> Self {
+        self.tailoring
+            .insert(lowercase(c), Tailoring::Expansion(text.to_owned()));
+        self
+    }
+
+    /// Sorts `letter`, in either case, as a base letter of its own right after `after`, and after
+    /// the letters previously tailored to follow `after`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SortError::Config`] if more than 15 letters follow the same letter.
+    pub fn letter_after(mut self, letter: char, after: char) -> Result<Self, SortError> {
+        let after = lowercase(after);
+        let mut weight = match self.tailoring.get(&after) {
+            Some(Tailoring::Letter(weight)) => *weight,
+            _ => self.elements(after).first().map_or(base(after), |e| e.base),
+        } + 1;
+        let taken = |weight: u32| {
+            self.tailoring
+                .values()
+                .any(|t| *t == Tailoring::Letter(weight))
+        };
+        while taken(weight) {
+            weight += 1;
+        }
+        if weight & 0xf == 0 {
+            return Err(SortError::Config(format!(
+                "at most 15 letters can follow `{}`",
+                after
+            )));
+        }
+        self.tailoring
+            .insert(lowercase(letter), Tailoring::Letter(weight));
+        Ok(self)
+    }
+
+    /// Returns the most bytes a sort key takes for every byte of the text, not counting the two
+    /// bytes separating its weights.
+    pub(crate) fn key_expansion(&self) -> usize {
+        // Characters of the built-in table weigh at most one element for every byte of them.
+        let elements = self
+            .tailoring
+            .keys()
+            .map(|&c| self.elements(c).len().div_ceil(c.len_utf8()))
+            .fold(1, usize::max);
+        let element = match self.strength {
+            Strength::Primary => 4,
+            Strength::Secondary => 5,
+            Strength::Tertiary => 6,
+        };
+        elements * element
+    }
+
+    /// Returns the collation elements of a lowercase character.
+    fn elements(&self, c: char) -> Vec<Element> {
+        let mut elements = Vec::new();
+        self.push_elements(c, 1, &mut elements);
+        elements
+    }
+
+    /// Appends the collation elements of a character to `out`.
+    fn push_elements(&self, c: char, case: u8, out: &mut Vec<Element>) {
+        let lower = lowercase(c);
+        let case = if lower != c { 2 } else { case };
+        match self.tailoring.get(&lower) {
+            Some(Tailoring::Expansion(text)) => {
+                for c in text.chars() {
+                    push_default(lowercase(c), case, out);
+                }
+            }
+            Some(&Tailoring::Letter(base)) => out.push(Element {
+                base,
+                accent: NO_ACCENT,
+                case,
+            }),
+            None => push_default(lower, case, out),
+        }
+    }
+
+    /// Appends the sort key of `text` to `key`. Sort keys compare byte by byte the way the texts
+    /// collate. Invalid UTF-8 sequences weigh as the replacement character.
+    pub(crate) fn sort_key(&self, text: &[u8], key: &mut Vec<u8>) {
+        let mut elements = Vec::new();
+        for c in String::from_utf8_lossy(text).chars() {
+            self.push_elements(c, 1, &mut elements);
+        }
+        // Base weights never start with a zero byte, so a zero ends them.
+        for element in elements.iter().filter(|e| e.base != 0) {
+            key.extend_from_slice(&element.base.to_be_bytes());
+        }
+        if self.strength >= Strength::Secondary {
+            key.push(0);
+            key.extend(elements.iter().map(|e| e.accent));
+        }
+        if self.strength >= Strength::Tertiary {
+            key.push(0);
+            key.extend(elements.iter().map(|e| e.case).filter(|&case| case != 0));
+        }
+    }
+}
+
+/// Appends the collation elements of a lowercase character of the built-in table.
+fn push_default(c: char, case: u8, out: &mut Vec<Element>) {
+    let accent_element = |accent| Element {
+        base: 0,
+        accent,
+        case: 0,
+    };
+    if let Some(accent) = accent(c) {
+        out.push(accent_element(accent));
+    } else if let Ok(i) = LATIN.binary_search_by_key(&c, |&(c, ..)| c) {
+        let (_, letters, mark) = LATIN[i];
+        for letter in letters.chars() {
+            out.push(Element {
+                base: base(letter),
+                accent: NO_ACCENT,
+                case,
+            });
+        }
+        out.extend(mark.and_then(accent).map(accent_element));
+    } else if !c.is_control() {
+        out.push(Element {
+            base: base(c),
+            accent: NO_ACCENT,
+            case,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_sorted(collation: &Collation, sorted: &[&str]) -> bool {
+        let key = |text: &str| {
+            let mut key = Vec::new();
+            collation.sort_key(text.as_bytes(), &mut key);
+            key
+        };
+        sorted.windows(2).all(|w| key(w[0]) < key(w[1]))
+    }
+
+    #[test]
+    fn should_collate_accents_and_case() {
+        assert!(LATIN.windows(2).all(|w| w[0].0 < w[1].0));
+        let tertiary = Collation::new(Strength::Tertiary);
+        let sorted = [
+            "", " z", "-a", "1", "a", "A", "á", "Á", "ab", "áb", "b", "e", "é", "ê", "ez", "Ħ",
+            "i", "o", "ø", "p", "z", "α", "Ω",
+        ];
+        assert!(is_sorted(&tertiary, &sorted), "{:?}", sorted);
+        // Decomposed accents weigh as the composed ones.
+        assert!(!is_sorted(&tertiary, &["e\u{301}", "é"]));
+        assert!(!is_sorted(&tertiary, &["é", "e\u{301}"]));
+        assert!(!is_sorted(&Collation::new(Strength::Primary), &["a", "Á"]));
+        assert!(!is_sorted(
+            &Collation::new(Strength::Secondary),
+            &["a", "A"]
+        ));
+        assert!(is_sorted(&Collation::new(Strength::Secondary), &["A", "á"]));
+    }
+
+    #[test]
+    fn should_tailor_letters() {
+        let swedish = Collation::new(Strength::Tertiary)
+            .letter_after('å', 'z')
+            .and_then(|c| c.letter_after('ä', 'å'))
+            .and_then(|c| c.letter_after('ö', 'ä'))
+            .unwrap();
+        assert!(is_sorted(&swedish, &["a", "ob", "z", "Å", "Ä", "äa", "ö"]));
+        let crowded = "αβγδεζηθικλμνξο"
+            .chars()
+            .try_fold(Collation::default(), |c, letter| {
+                c.letter_after(letter, 'z')
+            })
+            .unwrap();
+        assert!(matches!(
+            crowded.letter_after('π', 'z'),
+            Err(SortError::Config(_))
+        ));
+        let phone_book = Collation::new(Strength::Tertiary).expand('ä', "ae");
+        assert!(is_sorted(&phone_book, &["ad", "ae", "äa", "Äa", "af"]));
+        assert_eq!(phone_book.key_expansion(), 6);
+        assert_eq!(phone_book.expand('x', "xyz").key_expansion(), 18);
+    }
+}
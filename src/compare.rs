@@ -0,0 +1,115 @@
+use crate::KeySpec;
+use std::cmp::Ordering;
+use std::fmt;
+use std::sync::Arc;
+
+/// Compares two records given without their terminators.
+pub type Comparator = Arc<dyn Fn(&[u8], &[u8]) -> Ordering + Send + Sync>;
+
+/// Order in which records are sorted.
+#[derive(Clone, Default)]
+pub(crate) struct Compare {
+    custom: Option<Comparator>,
+    reverse: bool,
+    /// Keys the records are compared on before the whole records.
+    keys: Vec<KeySpec>,
+    separator: Option<u8>,
+    /// Whether records with equal keys are left equal, instead of being compared as a whole.
+    stable: bool,
+}
+
+impl Compare {
+    /// Tells whether records are simply compared byte by byte in ascending order.
+    pub(crate) fn is_natural(&self) -> bool {
+        self.custom.is_none() && !self.reverse && self.keys.is_empty()
+    }
+
+    /// Tells whether records are compared by a custom comparator or on keys.
+    pub(crate) fn is_custom(&self) -> bool {
+        self.custom.is_some() || !self.keys.is_empty()
+    }
+
+    pub(crate) fn is_reverse(&self) -> bool {
+        self.reverse
+    }
+
+    pub(crate) fn is_stable(&self) -> bool {
+        self.stable
+    }
+
+    /// Compares the records on the keys, falling back to the whole records unless the order is
+    /// stable.
+    pub(crate) fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let ord = self.cmp_keys(a, b);
+        if self.stable {
+            ord
+        } else {
+            ord.then_with(|| self.cmp_whole(a, b))
+        }
+    }
+
+    /// Compares the records on the keys only, or on the whole records if there are no keys. Records
+    /// equal this way are duplicates.
+    pub(crate) fn cmp_keys(&self, a: &[u8], b: &[u8]) -> Ordering {
+        if self.keys.is_empty() {
+            return self.cmp_whole(a, b);
+        }
+        self.keys
+            .iter()
+            .map(|key| {
+                let ord = key.cmp(a, b, self.separator);
+                // Like with `sort`, keys with options of their own ignore the global reverse.
+                if self.reverse && !key.has_options() {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            })
+            .find(|ord| ord.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+
+    fn cmp_whole(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let ord = match &self.custom {
+            Some(custom) => custom(a, b),
+            None => a.cmp(b),
+        };
+        if self.reverse {
+            ord.reverse()
+        } else {
+            ord
+        }
+    }
+
+    pub(crate) fn set_reverse(&mut self, reverse: bool) {
+        self.reverse = reverse;
+    }
+
+    pub(crate) fn set_custom(&mut self, custom: Comparator) {
+        self.custom = Some(custom);
+    }
+
+    pub(crate) fn add_key(&mut self, key: KeySpec) {
+        self.keys.push(key);
+    }
+
+    pub(crate) fn set_separator(&mut self, separator: Option<u8>) {
+        self.separator = separator;
+    }
+
+    pub(crate) fn set_stable(&mut self, stable: bool) {
+        self.stable = stable;
+    }
+}
+
+impl fmt::Debug for Compare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Compare")
+            .field("custom", &self.custom.is_some())
+            .field("reverse", &self.reverse)
+            .field("keys", &self.keys)
+            .field("separator", &self.separator)
+            .field("stable", &self.stable)
+            .finish()
+    }
+}
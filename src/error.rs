@@ -0,0 +1,91 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Phase of sorting during which an I/O operation failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Reading the input and writing sorted runs to a temporary file.
+    RunGeneration,
+    /// Merging runs into bigger ones or into the output file.
+    Merge,
+    /// Moving the result to the output path.
+    Rename,
+    /// Reading the input to check whether it's sorted.
+    Check,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::RunGeneration => f.write_str("run generation"),
+            Phase::Merge => f.write_str("merge"),
+            Phase::Rename => f.write_str("rename"),
+            Phase::Check => f.write_str("check"),
+        }
+    }
+}
+
+/// Error returned by sorting.
+#[derive(Debug)]
+pub enum SortError {
+    /// The sorting is misconfigured, e.g. the cache size is too small.
+    Config(String),
+    /// An I/O operation on `path` failed.
+    Io {
+        phase: Phase,
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// The input contains a record which can't be parsed.
+    MalformedRecord { offset: u64, reason: String },
+    /// The input or the configuration exceeds what the sorter can handle.
+    Limit(String),
+}
+
+impl fmt::Display for SortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SortError::Config(msg) => write!(f, "invalid configuration: {}", msg),
+            SortError::Io {
+                phase,
+                path,
+                source,
+            } => write!(
+                f,
+                "I/O error during {} on `{}`: {}",
+                phase,
+                path.display(),
+                source
+            ),
+            SortError::MalformedRecord { offset, reason } => {
+                write!(f, "malformed record at byte {}: {}", offset, reason)
+            }
+            SortError::Limit(msg) => write!(f, "limit exceeded: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SortError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SortError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Tags I/O errors with the phase and the path of the failed operation.
+pub(crate) trait IoResultExt<T> {
+    fn during(self, phase: Phase, path: &Path) -> Result<T, SortError>;
+}
+
+impl<T> IoResultExt<T> for io::Result<T> {
+    fn during(self, phase: Phase, path: &Path) -> Result<T, SortError> {
+        self.map_err(|source| SortError::Io {
+            phase,
+            path: path.to_owned(),
+            source,
+        })
+    }
+}
@@ -0,0 +1,74 @@
+//! Sorting of single bytes in their natural order by counting them, without any runs.
+
+use crate::error::IoResultExt;
+use crate::{Phase, SortError};
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// Size of the buffer the input is read with and the output is written with, unless the memory
+/// budget is smaller.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Number of occurrences of each byte value.
+pub(crate) struct Histogram {
+    counts: [u64; 256],
+    buffer: Vec<u8>,
+}
+
+impl Histogram {
+    /// Creates an empty histogram reading and writing with buffers of at most `memory` bytes.
+    pub(crate) fn new(memory: usize) -> Self {
+        Histogram {
+            counts: [0; 256],
+            buffer: vec![0; memory.clamp(1, BUFFER_SIZE)],
+        }
+    }
+
+    /// Counts the bytes of `input` in one pass. Returns the number of bytes read.
+    pub(crate) fn count<R: Read>(&mut self, mut input: R, path: &Path) -> Result<u64, SortError> {
+        let mut bytes = 0;
+        loop {
+            let n = input
+                .read(&mut self.buffer)
+                .during(Phase::RunGeneration, path)?;
+            if n == 0 {
+                return Ok(bytes);
+            }
+            for &b in &self.buffer[..n] {
+                self.counts[b as usize] += 1;
+            }
+            bytes += n as u64;
+        }
+    }
+
+    /// Writes the counted bytes to `output` in ascending or descending order, each of them once
+    /// with `unique` set.
+    pub(crate) fn write<W: Write>(
+        &mut self,
+        mut output: W,
+        path: &Path,
+        descending: bool,
+        unique: bool,
+    ) -> Result<(), SortError> {
+        let mut values: Vec<u8> = (0..=u8::MAX).collect();
+        if descending {
+            values.reverse();
+        }
+        for value in values {
+            let mut count = self.counts[value as usize];
+            if unique {
+                count = count.min(1);
+            }
+            let len = count.min(self.buffer.len() as u64) as usize;
+            self.buffer[..len].fill(value);
+            while count != 0 {
+                let n = count.min(len as u64) as usize;
+                output
+                    .write_all(&self.buffer[..n])
+                    .during(Phase::Merge, path)?;
+                count -= n as u64;
+            }
+        }
+        output.flush().during(Phase::Merge, path)
+    }
+}
@@ -0,0 +1,301 @@
+use crate::numeric::{cmp_general, cmp_human, cmp_numeric};
+use crate::version::cmp_version;
+use crate::SortError;
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// Position of a key boundary within a line, as in `sort -k`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct KeyPos {
+    /// Number of the field, starting from 1.
+    field: usize,
+    /// Number of the character within the field, starting from 1. Zero stands for the end of the
+    /// field, which is only allowed for the end of a key.
+    char: usize,
+    /// Whether leading blanks of the field are skipped before counting characters.
+    skip_blanks: bool,
+}
+
+/// How the text of a key is interpreted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum KeyKind {
+    #[default]
+    Text,
+    /// Decimal number (`n`).
+    Numeric,
+    /// Floating point number in any notation (`g`).
+    GeneralNumeric,
+    /// Number with an SI suffix (`h`).
+    HumanNumeric,
+    /// Version number or file name (`V`).
+    Version,
+}
+
+/// Modifiers of how the text of a key is compared.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct KeyOptions {
+    kind: KeyKind,
+    /// Only blanks and alphanumeric characters are compared (`d`).
+    dictionary: bool,
+    /// Lowercase letters are compared as uppercase ones (`f`).
+    fold_case: bool,
+    /// Only printable characters are compared (`i`).
+    printable: bool,
+    /// The key is compared in descending order (`r`).
+    reverse: bool,
+}
+
+/// Part of a line records are compared on, parsed from the `sort -k` syntax
+/// `FIELD[.CHAR][OPTS][,FIELD[.CHAR][OPTS]]`.
+///
+/// Fields and characters are numbered from 1, and a missing or zero end character stands for the
+/// end of the field. Without the end position the key lasts till the end of the line. The options
+/// are:
+/// - `b` - skip leading blanks of the field,
+/// - `d` - compare only blanks and alphanumeric characters,
+/// - `f` - fold lowercase letters to uppercase,
+/// - `i` - compare only printable characters,
+/// - `n` - compare decimal numbers, such as `-12.5`,
+/// - `g` - compare floating point numbers in any notation, such as `1.5e3` or `inf`,
+/// - `h` - compare numbers with SI suffixes, such as `2K` or `1G`,
+/// - `V` - compare versions and file names, such as `v1.10.2` or `img12.png`, with numbers in them
+///   compared as numbers,
+/// - `r` - reverse the order of the key.
+///
+/// Text which isn't a number is compared as zero with `n` and `h`, and before all numbers with `g`.
+///
+/// ```
+/// use big_file_sort::KeySpec;
+///
+/// // The second field, case-insensitively.
+/// let key: KeySpec = "2,2f".parse()?;
+/// // The third field as a number, from the largest.
+/// let key: KeySpec = "3,3nr".parse()?;
+/// # Ok::<(), big_file_sort::SortError>(())
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeySpec {
+    start: KeyPos,
+    end: Option<KeyPos>,
+    options: KeyOptions,
+}
+
+impl FromStr for KeySpec {
+    type Err = SortError;
+
+    fn from_str(spec: &str) -> Result<Self, SortError> {
+        let invalid =
+            |reason: &str| SortError::Config(format!("invalid key `{}`: {}", spec, reason));
+        let mut options = KeyOptions::default();
+        let mut parse_pos = |pos: &str, is_end: bool| {
+            let digits_end = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            let field_len = digits_end(pos);
+            let field = pos[..field_len]
+                .parse::<usize>()
+                .map_err(|_| invalid("missing field number"))?;
+            let mut rest = &pos[field_len..];
+            let mut char = if is_end { 0 } else { 1 };
+            if let Some(chars) = rest.strip_prefix('.') {
+                let char_len = digits_end(chars);
+                char = chars[..char_len]
+                    .parse()
+                    .map_err(|_| invalid("missing character number"))?;
+                rest = &chars[char_len..];
+            }
+            if field == 0 {
+                return Err(invalid("fields are numbered from 1"));
+            }
+            if char == 0 && !is_end {
+                return Err(invalid("characters are numbered from 1"));
+            }
+            let mut skip_blanks = false;
+            for option in rest.chars() {
+                match option {
+                    'b' => skip_blanks = true,
+                    'd' => options.dictionary = true,
+                    'f' => options.fold_case = true,
+                    'i' => options.printable = true,
+                    'r' => options.reverse = true,
+                    'n' | 'g' | 'h' | 'V' => {
+                        let kind = match option {
+                            'n' => KeyKind::Numeric,
+                            'g' => KeyKind::GeneralNumeric,
+                            'h' => KeyKind::HumanNumeric,
+                            _ => KeyKind::Version,
+                        };
+                        if options.kind != KeyKind::Text && options.kind != kind {
+                            return Err(invalid("options `n`, `g`, `h` and `V` are incompatible"));
+                        }
+                        options.kind = kind;
+                    }
+                    _ => return Err(invalid(&format!("unknown option `{}`", option))),
+                }
+            }
+            Ok(KeyPos {
+                field,
+                char,
+                skip_blanks,
+            })
+        };
+        let (start, end) = match spec.split_once(',') {
+            Some((start, end)) => (parse_pos(start, false)?, Some(parse_pos(end, true)?)),
+            None => (parse_pos(spec, false)?, None),
+        };
+        Ok(KeySpec {
+            start,
+            end,
+            options,
+        })
+    }
+}
+
+fn is_blank(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+fn skip_blanks(line: &[u8], pos: usize) -> usize {
+    pos + line[pos..].iter().take_while(|&&b| is_blank(b)).count()
+}
+
+/// Returns the start and the end of the field with the zero-based `index`. Fields are separated by
+/// `separator`, or else each of them consists of blanks followed by non-blanks.
+fn field(line: &[u8], separator: Option<u8>, index: usize) -> (usize, usize) {
+    let end_of = |start: usize| match separator {
+        Some(sep) => start + line[start..].iter().take_while(|&&b| b != sep).count(),
+        None => {
+            let pos = skip_blanks(line, start);
+            pos + line[pos..].iter().take_while(|&&b| !is_blank(b)).count()
+        }
+    };
+    let mut start = 0;
+    for _ in 0..index {
+        let end = end_of(start);
+        if end == line.len() {
+            return (end, end);
+        }
+        start = match separator {
+            Some(_) => end + 1,
+            None => end,
+        };
+    }
+    (start, end_of(start))
+}
+
+impl KeySpec {
+    /// Returns the part of `line` making up the key.
+    fn extract<'l>(&self, line: &'l [u8], separator: Option<u8>) -> &'l [u8] {
+        let position = |pos: &KeyPos| {
+            let (mut start, end) = field(line, separator, pos.field - 1);
+            if pos.skip_blanks {
+                start = skip_blanks(line, start).min(end);
+            }
+            (start, end)
+        };
+        // Like with `sort`, character offsets may run past their field up to the end of the line.
+        let start = (position(&self.start).0 + self.start.char - 1).min(line.len());
+        let end = match &self.end {
+            None => line.len(),
+            Some(pos) if pos.char == 0 => position(pos).1,
+            Some(pos) => (position(pos).0 + pos.char).min(line.len()),
+        };
+        &line[start..end.max(start)]
+    }
+
+    /// Tells whether the key has options of its own, so the global ones don't apply to it.
+    pub(crate) fn has_options(&self) -> bool {
+        let skip_blanks = self.start.skip_blanks || self.end.is_some_and(|end| end.skip_blanks);
+        skip_blanks || self.options != KeyOptions::default()
+    }
+
+    /// Compares the keys of two lines.
+    pub(crate) fn cmp(&self, a: &[u8], b: &[u8], separator: Option<u8>) -> Ordering {
+        let ord = self.cmp_keys(self.extract(a, separator), self.extract(b, separator));
+        if self.options.reverse {
+            ord.reverse()
+        } else {
+            ord
+        }
+    }
+
+    /// Compares two keys in ascending order.
+    fn cmp_keys(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let options = self.options;
+        match options.kind {
+            KeyKind::Text if !(options.dictionary || options.fold_case || options.printable) => {
+                return a.cmp(b)
+            }
+            KeyKind::Text => {}
+            KeyKind::Numeric => return cmp_numeric(a, b),
+            KeyKind::GeneralNumeric => return cmp_general(a, b),
+            KeyKind::HumanNumeric => return cmp_human(a, b),
+            KeyKind::Version => return cmp_version(a, b),
+        }
+        let keep = |b: &&u8| {
+            (!options.dictionary || b.is_ascii_alphanumeric() || is_blank(**b))
+                && (!options.printable || (b' '..=b'~').contains(*b))
+        };
+        let fold = |b: &u8| {
+            if options.fold_case {
+                b.to_ascii_uppercase()
+            } else {
+                *b
+            }
+        };
+        a.iter()
+            .filter(keep)
+            .map(fold)
+            .cmp(b.iter().filter(keep).map(fold))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(spec: &str, line: &str, separator: Option<u8>) -> String {
+        let spec: KeySpec = spec.parse().unwrap();
+        String::from_utf8(spec.extract(line.as_bytes(), separator).to_vec()).unwrap()
+    }
+
+    #[test]
+    fn should_extract_keys() {
+        let line = "  ab cd\tef  gh";
+        assert_eq!(key("1", line, None), line);
+        assert_eq!(key("2,2", line, None), " cd");
+        assert_eq!(key("2b,2", line, None), "cd");
+        assert_eq!(key("2.2b,3.2", line, None), "d\te");
+        assert_eq!(key("2.2b,3.2b", line, None), "d\tef");
+        assert_eq!(key("3.9,3", line, None), "");
+        assert_eq!(key("5", line, None), "");
+        assert_eq!(key("2,2", "a:b:c", Some(b':')), "b");
+        assert_eq!(key("2", "a:b:c", Some(b':')), "b:c");
+        assert_eq!(key("2,3.1", "a::c", Some(b':')), ":c");
+        assert_eq!(key("4", "a:b:c", Some(b':')), "");
+        assert_eq!(key("1.2,1.3", "1 b", None), " b");
+        assert_eq!(key("1.2,1.3", "1:b", Some(b':')), ":b");
+        assert_eq!(key("2.3", "a:b:c", Some(b':')), "c");
+    }
+
+    #[test]
+    fn should_parse_options() {
+        let spec: KeySpec = "1.2bf,2di".parse().unwrap();
+        assert!(spec.start.skip_blanks);
+        assert_eq!(spec.start.char, 2);
+        assert!(!spec.end.unwrap().skip_blanks);
+        assert!(spec.options.dictionary && spec.options.fold_case && spec.options.printable);
+        let spec: KeySpec = "1,1nr".parse().unwrap();
+        assert!(spec.options.reverse && spec.has_options());
+        assert!(!"1.2,3".parse::<KeySpec>().unwrap().has_options());
+        assert!("1,3b".parse::<KeySpec>().unwrap().has_options());
+        assert_eq!(spec.cmp(b"9", b"10", None), Ordering::Greater);
+        assert!("0".parse::<KeySpec>().is_err());
+        assert!("1.0".parse::<KeySpec>().is_err());
+        assert!("1x".parse::<KeySpec>().is_err());
+        assert!("1n,2g".parse::<KeySpec>().is_err());
+        assert_eq!(
+            "2,2h".parse::<KeySpec>().unwrap().options.kind,
+            KeyKind::HumanNumeric
+        );
+        assert!(",2".parse::<KeySpec>().is_err());
+    }
+}
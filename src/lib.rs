@@ -1,190 +1,44 @@
-use std::fs;
-use std::fs::File;
-use std::io::{Error, Read, Seek, SeekFrom, Write};
+use error::IoResultExt;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 
-/// Used to sort a temporary file generated by `sort_file` function.
-struct FileSortHelper {
-    cache_size: u64,
-    caches_num: u64,
-    buffer_size: u64,
-    in_file: File,
-    in_file_path: PathBuf,
-    out_file: File,
-    in_buffers: Vec<Vec<u8>>,
-    in_buffers_pos: Vec<u64>,
-    in_buffers_index: Vec<u64>,
-    out_buffer: Vec<u8>,
-    tmp_buffer: Vec<u8>,
-    slices_per_cache: u64,
-    slices_per_last_cache: u64,
-    last_slice_size: u64,
-    last_slice_in_last_cache_size: u64,
-}
+mod check;
+mod collation;
+mod compare;
+mod error;
+mod histogram;
+mod key;
+mod loser_tree;
+mod merge;
+mod numeric;
+mod radix;
+mod record;
+mod runs;
+mod sorter;
+mod temp;
+mod version;
 
-impl FileSortHelper {
-    fn new(
-        cache_size: u64,
-        caches_num: u64,
-        in_file: File,
-        in_file_len: u64,
-        in_file_path: PathBuf,
-        out_file: File,
-    ) -> Result<Self, Error> {
-        let max_caches_num = cache_size - 1;
-        assert!(caches_num <= max_caches_num, "File is too big.");
-        let buffer_size = cache_size / (caches_num + 1);
-        // This should be always true, because we already checked that the file is not empty.
-        assert_ne!(buffer_size, 0, "file is not empty; qed");
-        let in_buffers = vec![Vec::<u8>::with_capacity(buffer_size as usize); caches_num as usize];
-        let in_buffers_pos = vec![buffer_size; caches_num as usize];
-        let in_buffers_index = vec![0; caches_num as usize];
-        let out_buffer = Vec::<u8>::with_capacity(buffer_size as usize);
-        let slices_per_cache = (cache_size + (buffer_size - 1)) / buffer_size;
-        let last_cache_size = cache_size - (cache_size * caches_num - in_file_len);
-        let slices_per_last_cache = (last_cache_size + (buffer_size - 1)) / buffer_size;
-        let last_slice_size = buffer_size - (slices_per_cache * buffer_size - cache_size);
-        let last_slice_in_last_cache_size =
-            buffer_size - (slices_per_last_cache * buffer_size - last_cache_size);
-        let tmp_buffer = vec![0u8; buffer_size as usize];
-
-        let mut sorter = FileSortHelper {
-            cache_size,
-            caches_num,
-            buffer_size,
-            in_file,
-            in_file_path,
-            out_file,
-            in_buffers,
-            in_buffers_pos,
-            in_buffers_index,
-            out_buffer,
-            tmp_buffer,
-            slices_per_cache,
-            slices_per_last_cache,
-            last_slice_size,
-            last_slice_in_last_cache_size,
-        };
-        sorter.init_buffers()?;
-        Ok(sorter)
-    }
-
-    /// Merges `in_buffers` into `out_buffer`.
-    fn merge(&mut self) -> Result<(), Error> {
-        loop {
-            let mut min_ind = 0;
-            let mut min = u8::MAX;
-            let mut changed = false;
-            for (i, &pos) in self.in_buffers_pos.iter().enumerate() {
-                if let Some(&m) = self.in_buffers[i].get(pos as usize) {
-                    if m < min {
-                        min = m;
-                        min_ind = i;
-                        changed = true;
-                    }
-                }
-            }
-            // No changes were occurred, which means we merged all the buffers.
-            if !changed {
-                break;
-            }
-            self.out_buffer.push(min);
-            self.in_buffers_pos[min_ind] += 1;
-            if self.in_buffers_pos[min_ind] as usize == self.in_buffers[min_ind].len() {
-                self.load_next_buffer(min_ind)?;
-            }
-            // We filled up the output buffer - write it out and clear.
-            if self.out_buffer.len() == self.buffer_size as usize {
-                self.out_file.write_all(&self.out_buffer)?;
-                self.out_buffer.clear();
-            }
-        }
-        // Write out the rest.
-        self.out_file.write_all(&self.out_buffer)?;
-        self.out_file.flush()?;
-        Ok(())
-    }
-
-    /// Loads a corresponding i-th buffer from the input file.
-    fn load_next_buffer(&mut self, i: usize) -> Result<(), Error> {
-        let in_buff = &mut self.in_buffers[i];
-        let is_last_buffer = i == (self.caches_num - 1) as usize;
-        let slice_ind = self.in_buffers_index[i];
-        let has_next_slice = if !is_last_buffer {
-            slice_ind != self.slices_per_cache
-        } else {
-            slice_ind != self.slices_per_last_cache
-        };
-        // When we have more slices to read - refill the buffer.
-        if has_next_slice {
-            in_buff.clear();
-            let is_last_slice = if !is_last_buffer {
-                slice_ind == (self.slices_per_cache - 1)
-            } else {
-                slice_ind == (self.slices_per_last_cache - 1)
-            };
-            let read_len = if !is_last_slice {
-                self.buffer_size
-            } else if !is_last_buffer {
-                self.last_slice_size
-            } else {
-                self.last_slice_in_last_cache_size
-            };
-            let read_buff = &mut self.tmp_buffer[..read_len as usize];
-            self.in_file.seek(SeekFrom::Start(
-                i as u64 * self.cache_size + slice_ind * self.buffer_size,
-            ))?;
-            self.in_file.read_exact(read_buff)?;
-            in_buff.extend_from_slice(read_buff);
-            self.in_buffers_pos[i] = 0;
-            self.in_buffers_index[i] += 1;
-        }
-        Ok(())
-    }
-
-    /// Initialized buffers.
-    fn init_buffers(&mut self) -> Result<(), Error> {
-        for (i, in_buff) in self.in_buffers.iter_mut().enumerate() {
-            let is_last_buffer = i == (self.caches_num - 1) as usize;
-            let slice_ind = self.in_buffers_index[i];
-            in_buff.clear();
-            let is_last_slice = if !is_last_buffer {
-                slice_ind == (self.slices_per_cache - 1)
-            } else {
-                slice_ind == (self.slices_per_last_cache - 1)
-            };
-            let read_len = if !is_last_slice {
-                self.buffer_size
-            } else if !is_last_buffer {
-                self.last_slice_size
-            } else {
-                self.last_slice_in_last_cache_size
-            };
-            let read_buff = &mut self.tmp_buffer[..read_len as usize];
-            self.in_file.read_exact(read_buff)?;
-            in_buff.extend_from_slice(read_buff);
-            self.in_buffers_pos[i] = 0;
-            self.in_buffers_index[i] += 1;
-            if !is_last_buffer {
-                self.in_file.seek(SeekFrom::Current(
-                    (self.cache_size - read_len + slice_ind * self.buffer_size) as i64,
-                ))?;
-            }
-        }
-        self.out_buffer.clear();
-        Ok(())
-    }
-}
+pub use check::Disorder;
+pub use collation::{Collation, Strength};
+pub use compare::Comparator;
+pub use error::{Phase, SortError};
+pub use key::KeySpec;
+pub use record::{Keep, RecordCodec, RecordFormat};
+pub use runs::RunStrategy;
+pub use sorter::{SortReport, Sorter, DEFAULT_MEMORY};
 
-/// Automatically drop the temporary file.
-impl Drop for FileSortHelper {
-    fn drop(&mut self) {
-        let _ = fs::remove_file(&self.in_file_path);
-    }
-}
+/// Smallest cache size which allows to merge at least two runs at once.
+pub const MIN_CACHE_SIZE: u64 = 3;
 
 /**
-Sorts the file content and returns output file path.
+Sorts the file content byte by byte and returns output file path.
+
+See [`sort_file_with_format`] to sort records other than single bytes, and [`Sorter`] for the rest
+of the settings.
+
+As there are only 256 different bytes, they are sorted by counting the occurrences of each of them
+in one pass over the file, and writing them out in order, without any temporary files. The
+algorithm below is used for the other records, and for bytes in the order of a comparator.
 
 File's content is divided by M parts each of size at max of our cache size (`C`) (basically RAM).
 
@@ -229,60 +83,822 @@ _Output file:_
 +--------+--------+-----+--------+
 ```
 
-Maximum file size is: `(cache_size + 1) ** 2` bytes. It can be improved to
-`((cache_size + 1) ** 2) * (2 ** 64)` by adding another abstraction over caches, but the idea will be
-the same.
-*/
-pub fn sort_file<P: AsRef<Path>>(path: P, cache_size: u64) -> Result<PathBuf, Error> {
-    // Prepare a temporary file.
-    let mut cache = Vec::<u8>::with_capacity(cache_size as usize);
+Parts already in ascending or descending order are joined into one run as long as each of them
+goes after (or before) the previous ones, so sorted input makes a single run. It's renamed into the
+output file without any merging, and reverse sorted input is copied part by part.
+
+At most `cache_size - 1` runs are merged at once, or half as many when the runs are spread over
+several temporary directories, since every run then gets a second buffer to read ahead. When there
+are more of them, every group of that many consecutive runs is merged into a bigger run of another
+temporary file, and this is repeated until the runs can be merged into the output file at once.
+So there is no limit on the file size, but the cache must be at least [`MIN_CACHE_SIZE`] bytes.
 
+A file of at most one byte is already sorted, so its own path is returned.
+*/
+pub fn sort_file<P: AsRef<Path>>(path: P, cache_size: u64) -> Result<PathBuf, SortError> {
     let path = path.as_ref();
-    let mut file = fs::File::open(path)?;
-    let out_file_path = path.with_extension("tmp.txt");
-    let mut file_out = fs::File::create(&out_file_path)?;
-
-    let mut caches_num = 0;
-    let mut file_len = 0;
-    let mut tmp_buffer = vec![0u8; cache_size as usize];
-    loop {
-        let n = file.read(&mut tmp_buffer)?;
-        if n == 0 {
-            break;
-        }
-        cache.extend_from_slice(&tmp_buffer[..n]);
-        cache.sort_unstable();
-        file_out.write_all(&cache)?;
-        cache.clear();
-        file_len += n as u64;
-        caches_num += 1;
-    }
-    if file_len <= 1 {
+    let bytes = std::fs::metadata(path)
+        .during(Phase::RunGeneration, path)?
+        .len();
+    if bytes <= 1 {
         println!("File is already sorted.");
-        fs::remove_file(out_file_path)?;
         return Ok(path.to_owned());
     }
-    // We have sorted the whole file. Return the temporary one.
-    if caches_num == 1 {
-        drop(file_out);
-        drop(file);
-        let out_path = path.with_extension("out.txt");
-        fs::rename(out_file_path, &out_path)?;
-        return Ok(out_path);
-    }
-    file = fs::File::open(&out_file_path)?;
-    // Here we should output to the initial file, but using another one for comparison.
-    let file_out_path = path.with_extension("out.txt");
-    file_out = fs::File::create(&file_out_path)?;
-    // Sort input file using the temporary one.
-    let mut sorter = FileSortHelper::new(
-        cache_size,
-        caches_num,
-        file,
-        file_len,
-        out_file_path,
-        file_out,
-    )?;
-    sorter.merge()?;
-    Ok(file_out_path)
+    sort_file_with_format(path, cache_size, RecordFormat::Bytes)
+}
+
+/// Sorts the records of the file and returns output file path.
+///
+/// Works the same way as [`sort_file`], but parts of the file are cut at record boundaries, so a
+/// record which straddles two parts goes to the latter one. A record longer than the cache is kept
+/// whole, at the cost of exceeding the cache size.
+pub fn sort_file_with_format<P: AsRef<Path>>(
+    path: P,
+    cache_size: u64,
+    format: RecordFormat,
+) -> Result<PathBuf, SortError> {
+    let report = Sorter::new(path.as_ref())
+        .memory(cache_size)
+        .format(format)
+        .run()?;
+    Ok(report.output.unwrap_or_default())
+}
+
+/// Sorts the file of records encoded by `codec` in the order of the records, and returns output
+/// file path.
+///
+/// Works the same way as [`sort_file`]. Every part of the file is decoded into records to be
+/// sorted, while the merge decodes only the records being compared and copies them as they are.
+pub fn sort_file_with_codec<P: AsRef<Path>, C: RecordCodec>(
+    path: P,
+    cache_size: u64,
+    codec: C,
+) -> Result<PathBuf, SortError> {
+    let report = Sorter::new(path.as_ref())
+        .memory(cache_size)
+        .run_with_codec(codec)?;
+    Ok(report.output.unwrap_or_default())
+}
+
+/// Sorts the records read from `input` and writes them to `output`. Sorted runs are spilled to
+/// temporary files, the final merge goes straight to `output`.
+pub fn sort_stream<R: Read, W: Write>(
+    input: R,
+    output: W,
+    cache_size: u64,
+    format: RecordFormat,
+) -> Result<(), SortError> {
+    Sorter::default()
+        .memory(cache_size)
+        .format(format)
+        .run_stream(input, output)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Creates a file with the given content in the temporary directory.
+    fn test_file(name: &str, content: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("big-file-sort-{}-{}.txt", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// Generates pseudo-random lines of length up to `max_len`, including empty ones.
+    fn random_lines(mut seed: u64, count: usize, max_len: usize) -> Vec<u8> {
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let mut content = Vec::new();
+        for _ in 0..count {
+            let len = next() as usize % (max_len + 1);
+            content.extend((0..len).map(|_| b' ' + (next() % 95) as u8));
+            content.push(b'\n');
+        }
+        content
+    }
+
+    /// Sorts lines in memory the same way `LC_ALL=C sort` does.
+    fn sort_lines(content: &[u8]) -> Vec<u8> {
+        if content.is_empty() {
+            return Vec::new();
+        }
+        let content = content.strip_suffix(b"\n").unwrap_or(content);
+        let mut lines: Vec<&[u8]> = content.split(|&b| b == b'\n').collect();
+        lines.sort();
+        lines
+            .iter()
+            .flat_map(|l| l.iter().chain(b"\n"))
+            .copied()
+            .collect()
+    }
+
+    fn check_lines(name: &str, content: &[u8], cache_size: u64) {
+        let path = test_file(name, content);
+        let sorted_path = sort_file_with_format(&path, cache_size, RecordFormat::Lines).unwrap();
+        let sorted = fs::read(&sorted_path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&sorted_path).unwrap();
+        assert_eq!(sorted, sort_lines(content));
+    }
+
+    #[test]
+    fn should_sort_lines_across_chunks() {
+        check_lines("lines", &random_lines(1, 300, 30), 128);
+    }
+
+    #[test]
+    fn should_sort_lines_longer_than_cache() {
+        check_lines("long-lines", &random_lines(2, 100, 300), 128);
+    }
+
+    #[test]
+    fn should_terminate_last_line() {
+        check_lines("unterminated", b"b\n\nc\na", 4);
+        check_lines("single", b"a", 4);
+        check_lines("empty", b"", 4);
+    }
+
+    #[test]
+    fn should_merge_in_multiple_passes() {
+        let content = random_lines(3, 400, 20);
+        check_lines("passes-lines", &content, 8);
+
+        let path = test_file("passes-bytes", &content);
+        let sorted_path = sort_file(&path, 5).unwrap();
+        let sorted = fs::read(&sorted_path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&sorted_path).unwrap();
+        let mut expected = content;
+        expected.sort_unstable();
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn should_reject_too_small_cache() {
+        let path = test_file("small-cache", b"ba");
+        let err = sort_file(&path, MIN_CACHE_SIZE - 1).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(err, SortError::Config(_)));
+    }
+
+    #[test]
+    fn should_tag_io_errors() {
+        let path = std::env::temp_dir().join("big-file-sort-missing.txt");
+        match sort_file(&path, 8).unwrap_err() {
+            SortError::Io {
+                phase,
+                path: err_path,
+                ..
+            } => {
+                assert_eq!(phase, Phase::RunGeneration);
+                assert_eq!(err_path, path);
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn should_sort_with_sorter_settings() {
+        let content = random_lines(4, 200, 20);
+        let path = test_file("sorter", &content);
+        let output = path.with_extension("sorted.txt");
+        let report = Sorter::new(&path)
+            .memory(256)
+            .format(RecordFormat::Lines)
+            .temp_dir(std::env::temp_dir())
+            .output(&output)
+            .comparator(|a, b| b.cmp(a))
+            .run()
+            .unwrap();
+        let sorted = fs::read(&output).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&output).unwrap();
+        let mut expected: Vec<&[u8]> = content.split_inclusive(|&b| b == b'\n').collect();
+        expected.sort_by(|a, b| b.cmp(a));
+        assert_eq!(sorted, expected.concat());
+        assert_eq!(report.output, Some(output));
+        assert_eq!(report.bytes, content.len() as u64);
+        assert!(report.runs > 1);
+        assert_eq!(report.merge_passes, 1);
+    }
+
+    #[test]
+    fn should_stripe_runs_over_temp_dirs() {
+        let content = random_lines(5, 500, 20);
+        let path = test_file("striped", &content);
+        let dirs: Vec<_> = (0..3)
+            .map(|i| path.with_extension(format!("tmp{}", i)))
+            .collect();
+        for dir in &dirs {
+            fs::create_dir_all(dir).unwrap();
+        }
+        let report = Sorter::new(&path)
+            .memory(16)
+            .format(RecordFormat::Lines)
+            .temp_dirs(&dirs)
+            .run()
+            .unwrap();
+        let sorted = fs::read(report.output.as_ref().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(report.output.as_ref().unwrap()).unwrap();
+        for dir in &dirs {
+            // Removing fails if any temporary file is left.
+            fs::remove_dir(dir).unwrap();
+        }
+        assert_eq!(sorted, sort_lines(&content));
+        assert!(report.merge_passes > 1);
+    }
+
+    #[test]
+    fn should_sort_in_parallel() {
+        let content = random_lines(6, 1000, 40);
+        let path = test_file("parallel", &content);
+        for &format in &[RecordFormat::Lines, RecordFormat::Bytes] {
+            let sorted: Vec<_> = [1, 3]
+                .iter()
+                .map(|&threads| {
+                    let report = Sorter::new(&path)
+                        .memory(256)
+                        .format(format)
+                        .parallelism(threads)
+                        .run()
+                        .unwrap();
+                    let sorted = fs::read(report.output.as_ref().unwrap()).unwrap();
+                    fs::remove_file(report.output.as_ref().unwrap()).unwrap();
+                    sorted
+                })
+                .collect();
+            assert_eq!(sorted[0], sorted[1]);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn should_count_bytes() {
+        let content = random_lines(12, 500, 40);
+        for &(reverse, unique) in &[(false, false), (true, false), (false, true), (true, true)] {
+            let sorter = Sorter::default()
+                .memory(128)
+                .reverse(reverse)
+                .unique(unique);
+            let mut counted = Vec::new();
+            let report = sorter
+                .clone()
+                .run_stream(&content[..], &mut counted)
+                .unwrap();
+            assert_eq!(report.bytes, content.len() as u64);
+            assert_eq!(report.runs, 0);
+            // The same order through runs and merging.
+            let mut merged = Vec::new();
+            let report = sorter
+                .comparator(|a, b| a.cmp(b))
+                .run_stream(&content[..], &mut merged)
+                .unwrap();
+            assert!(report.runs > 1);
+            assert_eq!(counted, merged);
+        }
+    }
+
+    #[test]
+    fn should_sort_stream() {
+        for &(content, cache_size) in &[
+            (&random_lines(7, 300, 30)[..], 64),
+            (&b"b\nc\na"[..], 64),
+            (&b""[..], 64),
+        ] {
+            let mut sorted = Vec::new();
+            sort_stream(content, &mut sorted, cache_size, RecordFormat::Lines).unwrap();
+            assert_eq!(sorted, sort_lines(content));
+        }
+    }
+
+    #[test]
+    fn should_sort_reverse() {
+        let content = random_lines(8, 500, 2);
+        let sorted = sort_lines(&content);
+        let mut expected: Vec<&[u8]> = sorted.split_inclusive(|&b| b == b'\n').collect();
+        expected.reverse();
+        for &parallelism in &[1, 3] {
+            let mut sorted = Vec::new();
+            Sorter::default()
+                .memory(64)
+                .format(RecordFormat::Lines)
+                .reverse(true)
+                .parallelism(parallelism)
+                .run_stream(&content[..], &mut sorted)
+                .unwrap();
+            assert_eq!(sorted, expected.concat());
+        }
+    }
+
+    #[test]
+    fn should_sort_reverse_unique() {
+        let content = random_lines(8, 500, 2);
+        let sorted = sort_lines(&content);
+        let mut expected: Vec<&[u8]> = sorted.split_inclusive(|&b| b == b'\n').collect();
+        expected.dedup();
+        expected.reverse();
+        for &parallelism in &[1, 3] {
+            let mut sorted = Vec::new();
+            Sorter::default()
+                .memory(64)
+                .format(RecordFormat::Lines)
+                .reverse(true)
+                .unique(true)
+                .parallelism(parallelism)
+                .run_stream(&content[..], &mut sorted)
+                .unwrap();
+            assert_eq!(sorted, expected.concat());
+        }
+    }
+
+    /// Strings prefixed with their length byte.
+    struct ShortStrings;
+
+    impl RecordCodec for ShortStrings {
+        type Record = String;
+
+        fn encode(&self, record: &String, buf: &mut Vec<u8>) {
+            buf.push(record.len() as u8);
+            buf.extend_from_slice(record.as_bytes());
+        }
+
+        fn decode(&self, buf: &[u8]) -> Result<Option<(String, usize)>, String> {
+            let len = match buf.first() {
+                Some(&len) if buf.len() > len as usize => len as usize,
+                _ => return Ok(None),
+            };
+            let record = String::from_utf8(buf[1..=len].to_vec()).map_err(|e| e.to_string())?;
+            Ok(Some((record, len + 1)))
+        }
+    }
+
+    #[test]
+    fn should_sort_with_codec() {
+        let content = random_lines(9, 400, 20);
+        let mut strings: Vec<String> = String::from_utf8(content)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        let mut encoded = Vec::new();
+        strings
+            .iter()
+            .for_each(|s| ShortStrings.encode(s, &mut encoded));
+        strings.sort();
+        let mut expected = Vec::new();
+        strings
+            .iter()
+            .for_each(|s| ShortStrings.encode(s, &mut expected));
+        for &parallelism in &[1, 3] {
+            let mut sorted = Vec::new();
+            let report = Sorter::default()
+                .memory(32)
+                .parallelism(parallelism)
+                .run_stream_with_codec(ShortStrings, &encoded[..], &mut sorted)
+                .unwrap();
+            assert!(report.merge_passes > 1);
+            assert_eq!(sorted, expected);
+        }
+
+        let path = test_file("codec", &encoded);
+        let sorted_path = sort_file_with_codec(&path, 256, ShortStrings).unwrap();
+        let sorted = fs::read(&sorted_path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&sorted_path).unwrap();
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn should_report_malformed_records() {
+        let mut sorted = Vec::new();
+        let result =
+            Sorter::default().run_stream_with_codec(ShortStrings, &b"\x01a\x02b"[..], &mut sorted);
+        match result {
+            Err(SortError::MalformedRecord { offset: 2, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    /// Single bytes encoded twice, which decode into the first of the two bytes only.
+    struct Doubled;
+
+    impl RecordCodec for Doubled {
+        type Record = u8;
+
+        fn encode(&self, &record: &u8, buf: &mut Vec<u8>) {
+            buf.extend_from_slice(&[record, record]);
+        }
+
+        fn decode(&self, buf: &[u8]) -> Result<Option<(u8, usize)>, String> {
+            Ok(buf.first().map(|&b| (b, 1)))
+        }
+    }
+
+    #[test]
+    fn should_report_records_not_decoding_back() {
+        let mut sorted = Vec::new();
+        let result = Sorter::default().run_stream_with_codec(Doubled, &b"ba"[..], &mut sorted);
+        match result {
+            Err(SortError::MalformedRecord { offset: 0, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_sort_fixed_size_records() {
+        let format = RecordFormat::Fixed {
+            size: 10,
+            key_offset: 2,
+            key_len: 3,
+        };
+        let content = random_lines(10, 400, 20);
+        let content = &content[..content.len() - content.len() % 10];
+        let key = |record: &[u8]| record[2..5].to_vec();
+        let mut records: Vec<&[u8]> = content.chunks(10).collect();
+        for &(cache_size, parallelism) in &[(64, 1), (1000, 3)] {
+            let mut sorted = Vec::new();
+            Sorter::default()
+                .memory(cache_size)
+                .format(format)
+                .parallelism(parallelism)
+                .run_stream(content, &mut sorted)
+                .unwrap();
+            let mut sorted: Vec<&[u8]> = sorted.chunks(10).collect();
+            assert!(sorted.windows(2).all(|w| key(w[0]) <= key(w[1])));
+            sorted.sort();
+            records.sort();
+            assert_eq!(sorted, records);
+        }
+
+        let mut sorted = Vec::new();
+        let result = Sorter::default()
+            .format(format)
+            .run_stream(&content[..15], &mut sorted);
+        match result {
+            Err(SortError::MalformedRecord { offset: 10, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_break_ties_of_fixed_size_records() {
+        let format = RecordFormat::Fixed {
+            size: 4,
+            key_offset: 0,
+            key_len: 1,
+        };
+        // Few distinct keys, so most records are equal on them but differ as a whole.
+        let records: Vec<[u8; 4]> = (0..5000u32)
+            .map(|i| {
+                let [a, b, c, d] = i.wrapping_mul(2_654_435_761).to_be_bytes();
+                [a % 4, b, c, d]
+            })
+            .collect();
+        let mut expected = records.clone();
+        expected.sort();
+        for &custom in &[false, true] {
+            for &(memory, parallelism) in &[(4 << 10, 1), (4 << 10, 3), (1 << 20, 1)] {
+                let mut sorter = Sorter::default()
+                    .memory(memory)
+                    .format(format)
+                    .parallelism(parallelism);
+                if custom {
+                    sorter = sorter.comparator(|a, b| a.cmp(b));
+                }
+                let mut sorted = Vec::new();
+                sorter
+                    .run_stream(&records.concat()[..], &mut sorted)
+                    .unwrap();
+                assert_eq!(sorted, expected.concat());
+            }
+        }
+    }
+
+    #[test]
+    fn should_sort_on_keys() {
+        let content = random_lines(11, 400, 6);
+        let lines: Vec<&[u8]> = content.split_inclusive(|&b| b == b'\n').collect();
+        // The third and the fourth characters, case-insensitively.
+        let key = |line: &[u8]| -> Vec<u8> {
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            line.iter()
+                .skip(2)
+                .take(2)
+                .map(u8::to_ascii_uppercase)
+                .collect()
+        };
+        let mut expected = lines.clone();
+        expected.sort_by(|a, b| key(a).cmp(&key(b)).then_with(|| a.cmp(b)));
+        // The first or the last of the lines with equal keys in the input order.
+        let mut first = lines.clone();
+        first.sort_by_key(|line| key(line));
+        let mut last = first.clone();
+        first.dedup_by(|a, b| key(a) == key(b));
+        last.reverse();
+        last.dedup_by(|a, b| key(a) == key(b));
+        last.reverse();
+        for &(unique, keep, expected) in &[
+            (false, Keep::First, &expected),
+            (true, Keep::First, &first),
+            (true, Keep::Last, &last),
+        ] {
+            let mut sorted = Vec::new();
+            Sorter::default()
+                .memory(64)
+                .format(RecordFormat::Lines)
+                .key("1.3f,1.4".parse().unwrap())
+                .field_separator(b'\t')
+                .unique(unique)
+                .keep(keep)
+                .run_stream(&content[..], &mut sorted)
+                .unwrap();
+            assert_eq!(sorted, expected.concat());
+        }
+    }
+
+    #[test]
+    fn should_reverse_keys_without_options() {
+        let mut sorted = Vec::new();
+        Sorter::default()
+            .format(RecordFormat::Lines)
+            .key("2,2".parse().unwrap())
+            .key("1,1n".parse().unwrap())
+            .reverse(true)
+            .run_stream(&b"2 a\n10 a\n1 b\n"[..], &mut sorted)
+            .unwrap();
+        assert_eq!(sorted, b"1 b\n2 a\n10 a\n");
+    }
+
+    #[test]
+    fn should_keep_order_of_equal_records() {
+        // Records of a key letter and a sequence number, compared on the letter only.
+        let records: Vec<[u8; 4]> = (0..500u16)
+            .map(|i| {
+                let [hi, lo] = i.to_be_bytes();
+                [b'a' + (i * 7 % 5) as u8, hi, lo, b'\n']
+            })
+            .collect();
+        let content = records.concat();
+        let mut expected = records.clone();
+        expected.sort_by_key(|record| record[0]);
+        let mut first = expected.clone();
+        first.dedup_by_key(|record| record[0]);
+        // Small runs merged in several passes, and a single big run.
+        for &(memory, parallelism, unique, expected) in &[
+            (32, 1, false, &expected),
+            (32, 3, false, &expected),
+            (32, 1, true, &first),
+            (1 << 16, 1, false, &expected),
+        ] {
+            let mut sorted = Vec::new();
+            Sorter::default()
+                .memory(memory)
+                .format(RecordFormat::Fixed {
+                    size: 4,
+                    key_offset: 0,
+                    key_len: 1,
+                })
+                .stable(true)
+                .unique(unique)
+                .parallelism(parallelism)
+                .run_stream(&content[..], &mut sorted)
+                .unwrap();
+            assert_eq!(sorted, expected.concat());
+        }
+    }
+
+    #[test]
+    fn should_generate_runs_by_replacement_selection() {
+        let content = random_lines(13, 2000, 200);
+        let sort = |content: &[u8], strategy, configure: &dyn Fn(Sorter) -> Sorter| {
+            let mut sorted = Vec::new();
+            let sorter = Sorter::default()
+                .memory(4096)
+                .format(RecordFormat::Lines)
+                .run_strategy(strategy);
+            let report = configure(sorter).run_stream(content, &mut sorted).unwrap();
+            (sorted, report.runs)
+        };
+        let (sorted, runs) = sort(&content, RunStrategy::ReplacementSelection, &|s| s);
+        let (_, chunk_runs) = sort(&content, RunStrategy::Chunks, &|s| s);
+        assert_eq!(sorted, sort_lines(&content));
+        assert!(runs * 4 < chunk_runs * 3, "{} vs {} runs", runs, chunk_runs);
+        // Sorted input makes a single run.
+        assert_eq!(
+            sort(&sorted, RunStrategy::ReplacementSelection, &|s| s).1,
+            1
+        );
+        let configs: [&dyn Fn(Sorter) -> Sorter; 4] = [
+            &|s| s.reverse(true),
+            &|s| s.key("1.1,1.1".parse().unwrap()).stable(true),
+            &|s| {
+                s.key("1.1,1.1".parse().unwrap())
+                    .unique(true)
+                    .keep(Keep::Last)
+            },
+            &|s| s.count(true),
+        ];
+        for configure in &configs {
+            assert_eq!(
+                sort(&content, RunStrategy::ReplacementSelection, configure).0,
+                sort(&content, RunStrategy::Chunks, configure).0
+            );
+        }
+    }
+
+    #[test]
+    fn should_join_runs_of_presorted_input() {
+        let sorted = sort_lines(&random_lines(14, 1000, 20));
+        let mut lines: Vec<&[u8]> = sorted.split_inclusive(|&b| b == b'\n').collect();
+        // Sorted halves make a run each, and the chunk between them another one.
+        let halves = [&lines[lines.len() / 2..], &lines[..lines.len() / 2]]
+            .concat()
+            .concat();
+        lines.reverse();
+        let descending = lines.concat();
+        for &(content, runs) in &[(&sorted, 1), (&descending, 1), (&halves, 3)] {
+            for &threads in &[1, 3] {
+                let path = test_file("presorted", content);
+                let report = Sorter::new(&path)
+                    .memory(1024)
+                    .format(RecordFormat::Lines)
+                    .parallelism(threads)
+                    .run()
+                    .unwrap();
+                let output = fs::read(report.output.as_ref().unwrap()).unwrap();
+                fs::remove_file(&path).unwrap();
+                fs::remove_file(report.output.as_ref().unwrap()).unwrap();
+                assert_eq!(output, sorted);
+                assert_eq!(
+                    (report.runs, report.merge_passes),
+                    (runs, usize::from(runs > 1))
+                );
+            }
+        }
+        // Equal records of chunks joined in descending order keep the input order.
+        let records: Vec<[u8; 2]> = (0..300u16)
+            .map(|i| [((299 - i) / 4) as u8, i as u8])
+            .collect();
+        let mut expected = records.clone();
+        expected.sort_by_key(|record| record[0]);
+        let mut first = expected.clone();
+        first.dedup_by_key(|record| record[0]);
+        for &(unique, expected) in &[(false, &expected), (true, &first)] {
+            let mut output = Vec::new();
+            // Chunks of 16 records, each of them taking two slices to be radix sorted.
+            let report = Sorter::default()
+                .memory(16 * (2 + 2 * 16))
+                .format(RecordFormat::Fixed {
+                    size: 2,
+                    key_offset: 0,
+                    key_len: 1,
+                })
+                .stable(true)
+                .unique(unique)
+                .run_stream(&records.concat()[..], &mut output)
+                .unwrap();
+            assert_eq!(output, expected.concat());
+            assert_eq!(report.merge_passes, 0);
+        }
+    }
+
+    #[test]
+    fn should_check_order() {
+        let check = |content: &[u8], configure: &dyn Fn(Sorter) -> Sorter| {
+            // A small memory makes records straddle the chunks.
+            let sorter = Sorter::default().memory(8).format(RecordFormat::Lines);
+            configure(sorter).check_stream(content).unwrap()
+        };
+        let sorted = sort_lines(&random_lines(15, 300, 20));
+        assert_eq!(check(&sorted, &|s| s), None);
+        assert_eq!(check(b"a\nb\nb\nc", &|s| s), None);
+        let disorder = |offset, line, record: &[u8]| {
+            Some(Disorder {
+                offset,
+                line,
+                record: record.to_vec(),
+            })
+        };
+        assert_eq!(check(b"a\nbb\nccc\nb\nd\n", &|s| s), disorder(9, 4, b"b\n"));
+        assert_eq!(check(b"a\nc\nb", &|s| s), disorder(4, 3, b"b"));
+        assert_eq!(
+            check(b"a\nb\nb\nc", &|s| s.unique(true)),
+            disorder(4, 3, b"b\n")
+        );
+        assert_eq!(check(b"c\nb\nb\na\n", &|s| s.reverse(true)), None);
+        assert_eq!(
+            check(b"1 b\n2 a\n", &|s| s.key("2".parse().unwrap())),
+            disorder(4, 2, b"2 a\n")
+        );
+        let path = test_file("check", b"a\nb\nc\n");
+        let checked = Sorter::new(&path)
+            .format(RecordFormat::Lines)
+            .reverse(true)
+            .check()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(checked.map(|disorder| disorder.line), Some(2));
+    }
+
+    #[test]
+    fn should_count_lines() {
+        let content: String = (0..600u32)
+            .map(|i| format!("line {}\n", i * i % 37))
+            .collect();
+        let mut counts = std::collections::BTreeMap::new();
+        for line in content.lines() {
+            *counts.entry(line).or_insert(0) += 1;
+        }
+        let expected: String = counts
+            .iter()
+            .map(|(line, count)| format!("{:>7} {}\n", count, line))
+            .collect();
+        // Counts of many runs summed up in several passes, and of a single run.
+        for &memory in &[64, 1 << 16] {
+            let mut counted = Vec::new();
+            let report = Sorter::default()
+                .memory(memory)
+                .format(RecordFormat::Lines)
+                .count(true)
+                .run_stream(content.as_bytes(), &mut counted)
+                .unwrap();
+            assert_eq!(report.merge_passes > 1, memory == 64);
+            assert_eq!(String::from_utf8(counted).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn should_sort_with_collation() {
+        let names: Vec<&str> = "Émile emile Zoë zoe Ångström angstrom Łukasz lukas Ölander Ørsted \
+             Çelik celik Straße strasse Ñuñez nunez Δημήτρης"
+            .split_whitespace()
+            .collect();
+        let content: String = (0..200)
+            .map(|i| format!("{} {}\n", names[i * 7 % names.len()], i % 3))
+            .collect();
+        let collation = Collation::new(Strength::Primary);
+        let key = |line: &str| {
+            let mut key = Vec::new();
+            collation.sort_key(line.as_bytes(), &mut key);
+            key
+        };
+        let mut expected: Vec<&str> = content.lines().collect();
+        let mut unique = expected.clone();
+        expected.sort_by(|a, b| key(a).cmp(&key(b)).then_with(|| a.cmp(b)).reverse());
+        unique.sort_by_key(|line| std::cmp::Reverse(key(line)));
+        unique.dedup_by(|a, b| key(a) == key(b));
+        for &(memory, unique_keys, expected) in &[
+            (256, false, &expected),
+            (256, true, &unique),
+            (1 << 20, false, &expected),
+        ] {
+            let mut sorted = Vec::new();
+            Sorter::default()
+                .memory(memory)
+                .format(RecordFormat::Lines)
+                .collation(collation.clone())
+                .reverse(true)
+                .unique(unique_keys)
+                .run_stream(content.as_bytes(), &mut sorted)
+                .unwrap();
+            let expected: String = expected.iter().map(|line| format!("{}\n", line)).collect();
+            assert_eq!(String::from_utf8(sorted).unwrap(), expected);
+        }
+        assert!(matches!(
+            Sorter::default()
+                .collation(collation)
+                .run_stream(&b""[..], Vec::new()),
+            Err(SortError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn should_sort_numbers() {
+        let numbers: Vec<i64> = (0..300).map(|i| (i * 7919) % 1000 - 500).collect();
+        let content: String = numbers.iter().map(|n| format!("{}\n", n)).collect();
+        let mut expected = numbers.clone();
+        expected.sort_unstable();
+        let mut sorted = Vec::new();
+        Sorter::default()
+            .memory(64)
+            .format(RecordFormat::Lines)
+            .key("1n".parse().unwrap())
+            .run_stream(content.as_bytes(), &mut sorted)
+            .unwrap();
+        let sorted: Vec<i64> = String::from_utf8(sorted)
+            .unwrap()
+            .lines()
+            .map(|line| line.parse().unwrap())
+            .collect();
+        assert_eq!(sorted, expected);
+    }
 }
@@ -0,0 +1,83 @@
+/// Tournament tree of losers used to pick the next element of a K-way merge in `O(log K)`.
+///
+/// The tree doesn't hold the elements themselves, only indices of their sources. The sources are
+/// compared with a `less(a, b)` callback telling whether the current element of source `a` goes
+/// before the current element of source `b`. It must be a strict order, so ties should be broken by
+/// the source index, and exhausted sources must go after everything else.
+pub(crate) struct LoserTree {
+    /// Loser of the match played in every inner node, `tree[0]` holds the overall winner. Leaves
+    /// are implicit: the source `i` is a leaf number `len + i`.
+    tree: Vec<usize>,
+    len: usize,
+}
+
+impl LoserTree {
+    /// Plays the whole tournament between `len` sources.
+    pub(crate) fn new<F: FnMut(usize, usize) -> bool>(len: usize, mut less: F) -> Self {
+        let mut tree = vec![0; len.max(1)];
+        let mut winners = vec![0; 2 * len];
+        for (i, winner) in winners[len..].iter_mut().enumerate() {
+            *winner = i;
+        }
+        for node in (1..len).rev() {
+            let (a, b) = (winners[2 * node], winners[2 * node + 1]);
+            let (winner, loser) = if less(b, a) { (b, a) } else { (a, b) };
+            tree[node] = loser;
+            winners[node] = winner;
+        }
+        if len > 1 {
+            tree[0] = winners[1];
+        }
+        LoserTree { tree, len }
+    }
+
+    /// Returns the source holding the least element.
+    pub(crate) fn winner(&self) -> usize {
+        self.tree[0]
+    }
+
+    /// Replays the matches of the winner once its current element has changed.
+    pub(crate) fn replay<F: FnMut(usize, usize) -> bool>(&mut self, mut less: F) {
+        let mut winner = self.tree[0];
+        let mut node = (winner + self.len) / 2;
+        while node != 0 {
+            if less(self.tree[node], winner) {
+                std::mem::swap(&mut self.tree[node], &mut winner);
+            }
+            node /= 2;
+        }
+        self.tree[0] = winner;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_merge_sources_in_order() {
+        let sources = [
+            vec![1, 4, 9],
+            vec![],
+            vec![2, 3, 10, 11],
+            vec![0, 4],
+            vec![5],
+        ];
+        let mut pos = vec![0; sources.len()];
+        let less = |pos: &[usize], a: usize, b: usize| match (
+            sources[a].get(pos[a]),
+            sources[b].get(pos[b]),
+        ) {
+            (Some(x), Some(y)) => (x, a) < (y, b),
+            (x, _) => x.is_some(),
+        };
+        let mut tree = LoserTree::new(sources.len(), |a, b| less(&pos, a, b));
+        let mut merged = Vec::new();
+        while let Some(&x) = sources[tree.winner()].get(pos[tree.winner()]) {
+            merged.push(x);
+            pos[tree.winner()] += 1;
+            tree.replay(|a, b| less(&pos, a, b));
+        }
+        assert_eq!(merged, vec![0, 1, 2, 3, 4, 4, 5, 9, 10, 11]);
+    }
+}
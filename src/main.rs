@@ -1,22 +1,128 @@
-use std::io::Error;
+mod cli;
 
-pub use big_file_sort::sort_file;
+use big_file_sort::{Phase, SortError};
+use cli::{Args, Check, Command};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::process::ExitCode;
 
-/// Size of available memory. Used by caches.
-pub const MEM_SIZE_BYTES: u64 = 64;
+/// Exit status for input out of order when checking it.
+const EXIT_DISORDER: u8 = 1;
+/// Exit status for invalid arguments or configuration.
+const EXIT_USAGE: u8 = 2;
+/// Exit status for I/O errors.
+const EXIT_IO: u8 = 3;
+/// Exit status for malformed input.
+const EXIT_MALFORMED: u8 = 4;
+/// Exit status for exceeded limits.
+const EXIT_LIMIT: u8 = 5;
 
-fn main() -> Result<(), Error> {
-    let _ = sort_file("big_file.txt", MEM_SIZE_BYTES)?;
-    Ok(())
+fn main() -> ExitCode {
+    let name = env!("CARGO_PKG_NAME");
+    match cli::parse(std::env::args_os().skip(1)) {
+        Ok(Command::Help) => println!("{}", cli::USAGE),
+        Ok(Command::Version) => println!("{} {}", name, env!("CARGO_PKG_VERSION")),
+        Ok(Command::Sort(args)) => {
+            let result = match args.check {
+                Some(mode) => check(&args, mode),
+                None => sort(&args).map(|()| ExitCode::SUCCESS),
+            };
+            return result.unwrap_or_else(|e| {
+                eprintln!("{}: {}", name, e);
+                ExitCode::from(exit_code(&e))
+            });
+        }
+        Err(msg) => {
+            eprintln!(
+                "{}: {}\nTry `{} --help` for more information.",
+                name, msg, name
+            );
+            return ExitCode::from(EXIT_USAGE);
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Sorts as the arguments say. Files are sorted with [`Sorter::run`], so the result is moved into
+/// place without copying when possible, and the standard streams with [`Sorter::run_stream`].
+///
+/// [`Sorter::run`]: big_file_sort::Sorter::run
+/// [`Sorter::run_stream`]: big_file_sort::Sorter::run_stream
+fn sort(args: &Args) -> Result<(), SortError> {
+    let sorter = args.sorter();
+    if let (Some(_), Some(output)) = (&args.input, &args.output) {
+        return sorter.output(output).run().map(drop);
+    }
+    let stdin = io::stdin();
+    let input: Box<dyn Read> = match &args.input {
+        Some(path) => Box::new(File::open(path).map_err(|source| SortError::Io {
+            phase: Phase::RunGeneration,
+            path: path.clone(),
+            source,
+        })?),
+        None => Box::new(stdin.lock()),
+    };
+    let stdout = io::stdout();
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(File::create(path).map_err(|source| SortError::Io {
+            phase: Phase::Merge,
+            path: path.clone(),
+            source,
+        })?),
+        None => Box::new(stdout.lock()),
+    };
+    sorter.run_stream(input, BufWriter::new(output)).map(drop)
+}
+
+/// Checks whether the input is sorted as the arguments say, and reports the first record out of
+/// order unless the check is quiet.
+fn check(args: &Args, mode: Check) -> Result<ExitCode, SortError> {
+    let sorter = args.sorter();
+    let disorder = match &args.input {
+        Some(_) => sorter.check()?,
+        None => sorter.check_stream(io::stdin().lock())?,
+    };
+    let disorder = match disorder {
+        Some(disorder) => disorder,
+        None => return Ok(ExitCode::SUCCESS),
+    };
+    if mode == Check::Diagnose {
+        let input = args
+            .input
+            .as_ref()
+            .map_or_else(|| "-".into(), |path| path.display().to_string());
+        let record = &disorder.record;
+        eprintln!(
+            "{}: {}:{}: disorder at byte {}: {}",
+            env!("CARGO_PKG_NAME"),
+            input,
+            disorder.line,
+            disorder.offset,
+            String::from_utf8_lossy(record.strip_suffix(b"\n").unwrap_or(record))
+        );
+    }
+    Ok(ExitCode::from(EXIT_DISORDER))
+}
+
+fn exit_code(error: &SortError) -> u8 {
+    match error {
+        SortError::Config(_) => EXIT_USAGE,
+        SortError::Io { .. } => EXIT_IO,
+        SortError::MalformedRecord { .. } => EXIT_MALFORMED,
+        SortError::Limit(_) => EXIT_LIMIT,
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use big_file_sort::sort_file;
     use std::fs;
 
+    /// Size of available memory. Used by caches.
+    const MEM_SIZE_BYTES: u64 = 64;
+
     #[test]
-    fn should_sort() -> Result<(), Error> {
+    fn should_sort() -> Result<(), Box<dyn std::error::Error>> {
         let file_name = "big_file.txt";
         let mut v0 = fs::read(file_name)?;
         v0.sort();
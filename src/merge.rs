@@ -0,0 +1,416 @@
+use crate::error::IoResultExt;
+use crate::loser_tree::LoserTree;
+use crate::record::{Keep, Records};
+use crate::temp::TempFile;
+use crate::{Phase, SortError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread;
+
+/// Sorted run stored in one of the temporary files.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Run {
+    /// Index of the temporary file holding the run.
+    pub(crate) file: usize,
+    /// Position of the run in the file.
+    pub(crate) range: Range<u64>,
+    /// Further parts of the run in the same file, the one read next going last. A run joined from
+    /// chunks of descending input is stored this way, as each chunk goes before the previous one.
+    pub(crate) next: Vec<Range<u64>>,
+}
+
+impl Run {
+    /// Creates a run stored in one part.
+    pub(crate) fn new(file: usize, range: Range<u64>) -> Self {
+        Run {
+            file,
+            range,
+            next: Vec::new(),
+        }
+    }
+
+    /// Returns the parts of the run in the order of reading.
+    pub(crate) fn parts(&self) -> impl Iterator<Item = &Range<u64>> {
+        std::iter::once(&self.range).chain(self.next.iter().rev())
+    }
+
+    /// Moves on to the next part once the current one is read.
+    fn advance(&mut self) {
+        if self.range.is_empty() {
+            if let Some(next) = self.next.pop() {
+                self.range = next;
+            }
+        }
+    }
+}
+
+/// Returns how many runs can be merged at once when they are spread over `files` temporary files.
+pub(crate) fn max_fan_in(cache_size: u64, files: usize) -> u64 {
+    // Every run gets a buffer (and another one for prefetching, if there are several files to
+    // read in parallel), and one more buffer is used for the output.
+    (cache_size - 1) / buffers_per_run(files)
+}
+
+fn buffers_per_run(files: usize) -> u64 {
+    if files > 1 {
+        2
+    } else {
+        1
+    }
+}
+
+/// Reads the next chunk of `run` into `chunk`, and advances the run. The chunk is about `size`
+/// bytes long, but it only keeps whole records, so it may be shorter or, when a record is longer
+/// than `size`, longer.
+fn read_chunk<F: Records>(
+    records: &F,
+    file: &mut TempFile,
+    run: &mut Range<u64>,
+    size: u64,
+    chunk: &mut Vec<u8>,
+) -> Result<(), SortError> {
+    chunk.clear();
+    // The run is exhausted - leave the chunk empty.
+    if run.start == run.end {
+        return Ok(());
+    }
+    file.file
+        .seek(SeekFrom::Start(run.start))
+        .during(Phase::Merge, &file.path)?;
+    loop {
+        // Read at least as much as we already have, so long records are loaded in a few steps.
+        let len = chunk.len();
+        let read_len = size.max(len as u64).min(run.end - run.start - len as u64);
+        chunk.resize(len + read_len as usize, 0);
+        file.file
+            .read_exact(&mut chunk[len..])
+            .during(Phase::Merge, &file.path)?;
+        let complete_len = records.run_len(chunk);
+        // A record is longer than the chunk - keep reading until we have it whole.
+        if complete_len != 0 {
+            chunk.truncate(complete_len);
+            break;
+        }
+    }
+    run.start += chunk.len() as u64;
+    Ok(())
+}
+
+/// Appends `record` of a run to `out` as it's written by the merge.
+fn push_record<F: Records>(records: &F, is_output: bool, record: &[u8], out: &mut Vec<u8>) {
+    if is_output {
+        records.write_output(record, out);
+    } else {
+        out.extend_from_slice(record);
+    }
+}
+
+/// Request to read the next chunk of a run into `chunk`.
+struct ChunkRequest {
+    run: usize,
+    range: Range<u64>,
+    chunk: Vec<u8>,
+}
+
+/// The read chunk and the rest of the run.
+type ChunkResponse = Result<(Vec<u8>, Range<u64>), SortError>;
+
+/// Serves chunk requests for runs of one temporary file until all the requests are sent.
+fn prefetch<F: Records>(
+    records: &F,
+    file: &mut TempFile,
+    size: u64,
+    requests: Receiver<ChunkRequest>,
+    responses: Vec<SyncSender<ChunkResponse>>,
+) {
+    for mut request in requests {
+        let run = request.run;
+        let response = read_chunk(records, file, &mut request.range, size, &mut request.chunk)
+            .map(|()| (request.chunk, request.range));
+        if responses[run].send(response).is_err() {
+            break;
+        }
+    }
+}
+
+/// Loads the next chunks of the runs being merged.
+enum Loader<'s> {
+    /// Reads the chunks on demand from the only temporary file.
+    Direct(&'s mut TempFile),
+    /// Reads the chunks ahead of time on a thread per temporary file. There is at most one
+    /// request in flight for each run.
+    Prefetch {
+        requests: Vec<Sender<ChunkRequest>>,
+        responses: Vec<Receiver<ChunkResponse>>,
+    },
+}
+
+impl Loader<'_> {
+    /// Starts reading the i-th run.
+    fn start(&mut self, i: usize, run: &Run, size: u64) {
+        if let Loader::Prefetch { requests, .. } = self {
+            if !run.range.is_empty() {
+                let _ = requests[run.file].send(ChunkRequest {
+                    run: i,
+                    range: run.range.clone(),
+                    chunk: Vec::with_capacity(size as usize),
+                });
+            }
+        }
+    }
+
+    /// Loads the next chunk of the i-th run into `buffer`.
+    fn load<F: Records>(
+        &mut self,
+        records: &F,
+        i: usize,
+        run: &mut Run,
+        size: u64,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), SortError> {
+        match self {
+            Loader::Direct(file) => {
+                read_chunk(records, file, &mut run.range, size, buffer)?;
+                run.advance();
+                Ok(())
+            }
+            Loader::Prefetch {
+                requests,
+                responses,
+            } => {
+                if run.range.is_empty() {
+                    buffer.clear();
+                    return Ok(());
+                }
+                let (chunk, rest) = responses[i]
+                    .recv()
+                    .expect("reader threads live until all the chunks are received; qed")?;
+                let chunk = mem::replace(buffer, chunk);
+                run.range = rest;
+                run.advance();
+                // Let the reader load the next chunk while we are merging this one.
+                if !run.range.is_empty() {
+                    let _ = requests[run.file].send(ChunkRequest {
+                        run: i,
+                        range: run.range.clone(),
+                        chunk,
+                    });
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Used to merge runs of temporary files generated by `sort_file` function.
+pub(crate) struct FileSortHelper<'a, F, W> {
+    records: &'a F,
+    buffer_size: u64,
+    in_files: &'a mut [TempFile],
+    out_file: &'a mut W,
+    out_file_path: &'a Path,
+    in_buffers: Vec<Vec<u8>>,
+    in_buffers_pos: Vec<u64>,
+    /// Length of the current record of each buffer, if any.
+    in_heads: Vec<Option<usize>>,
+    /// The unread part of each run.
+    in_runs: Vec<Run>,
+    out_buffer: Vec<u8>,
+    /// Which of equal records to keep, if only one of them is written.
+    unique: Option<Keep>,
+    /// The last of the records merged, when `unique` is set. It's written once a record not equal
+    /// to it comes, or the merge ends.
+    pending: Option<Vec<u8>>,
+    /// Whether the merge writes the sorted output rather than a run.
+    is_output: bool,
+}
+
+impl<'a, F: Records, W: Write> FileSortHelper<'a, F, W> {
+    /// Creates a helper merging `runs` of the input files. There must be at most
+    /// [`max_fan_in`] runs, so each of them (and the output) gets at least one byte of the cache.
+    pub(crate) fn new(
+        records: &'a F,
+        cache_size: u64,
+        runs: &[Run],
+        in_files: &'a mut [TempFile],
+        out_file: &'a mut W,
+        out_file_path: &'a Path,
+    ) -> Self {
+        let caches_num = runs.len() as u64;
+        debug_assert!(
+            caches_num <= max_fan_in(cache_size, in_files.len()),
+            "runs are merged in groups; qed"
+        );
+        let buffer_size = cache_size / (caches_num * buffers_per_run(in_files.len()) + 1);
+        // Whole records don't straddle the buffers.
+        let buffer_size = records.align(buffer_size as usize) as u64;
+        let in_buffers = vec![Vec::<u8>::with_capacity(buffer_size as usize); caches_num as usize];
+        let in_buffers_pos = vec![0; caches_num as usize];
+        let out_buffer = Vec::<u8>::with_capacity(buffer_size as usize);
+
+        FileSortHelper {
+            records,
+            buffer_size,
+            in_files,
+            out_file,
+            out_file_path,
+            in_buffers,
+            in_buffers_pos,
+            in_heads: vec![None; caches_num as usize],
+            in_runs: runs.to_vec(),
+            out_buffer,
+            unique: None,
+            pending: None,
+            is_output: false,
+        }
+    }
+
+    /// Makes the merge keep only one of equal records.
+    pub(crate) fn unique(mut self, unique: Option<Keep>) -> Self {
+        self.unique = unique;
+        self
+    }
+
+    /// Makes the merge write the records as they are output, rather than as they are stored in
+    /// the runs.
+    pub(crate) fn output(mut self, is_output: bool) -> Self {
+        self.is_output = is_output;
+        self
+    }
+
+    /// Returns the current record of the i-th buffer, if any.
+    fn current(&self, i: usize) -> Option<&[u8]> {
+        let pos = self.in_buffers_pos[i] as usize;
+        self.in_heads[i].map(|len| &self.in_buffers[i][pos..pos + len])
+    }
+
+    /// Finds the current record of the i-th buffer.
+    fn update_head(&mut self, i: usize) {
+        let buff = &self.in_buffers[i][self.in_buffers_pos[i] as usize..];
+        self.in_heads[i] = self.records.record_len(buff);
+    }
+
+    /// Tells whether the current record of the a-th buffer goes before the one of the b-th buffer.
+    /// Ties are broken by the buffer index, so equal records keep the order of the runs, and
+    /// exhausted buffers go last.
+    fn is_before(&self, a: usize, b: usize) -> bool {
+        match (self.current(a), self.current(b)) {
+            (Some(x), Some(y)) => self.records.cmp(x, y).then(a.cmp(&b)).is_lt(),
+            (x, _) => x.is_some(),
+        }
+    }
+
+    /// Merges the runs into the output file. Returns the number of bytes written.
+    ///
+    /// When the runs are spread over several temporary files, each of them is read by its own
+    /// thread, so the runs are read from several devices in parallel.
+    pub(crate) fn merge(&mut self) -> Result<u64, SortError> {
+        let in_files = mem::take(&mut self.in_files);
+        if let [in_file] = in_files {
+            return self.merge_with(&mut Loader::Direct(in_file));
+        }
+        thread::scope(|scope| {
+            let (senders, responses): (Vec<_>, Vec<_>) =
+                self.in_runs.iter().map(|_| sync_channel(1)).unzip();
+            let mut requests = Vec::with_capacity(in_files.len());
+            for in_file in in_files.iter_mut() {
+                let (sender, receiver) = channel();
+                requests.push(sender);
+                let (records, size, senders) = (self.records, self.buffer_size, senders.clone());
+                scope.spawn(move || prefetch(records, in_file, size, receiver, senders));
+            }
+            self.merge_with(&mut Loader::Prefetch {
+                requests,
+                responses,
+            })
+        })
+    }
+
+    /// Merges `in_buffers` into `out_buffer`.
+    fn merge_with(&mut self, loader: &mut Loader) -> Result<u64, SortError> {
+        self.init_buffers(loader)?;
+        let mut written = 0;
+        let mut tree = LoserTree::new(self.in_buffers.len(), |a, b| self.is_before(a, b));
+        loop {
+            let min_ind = tree.winner();
+            // The least record is missing, which means we merged all the buffers.
+            if self.current(min_ind).is_none() {
+                break;
+            }
+            let pos = self.in_buffers_pos[min_ind] as usize;
+            let len = self.current(min_ind).map_or(0, <[u8]>::len);
+            let record = &self.in_buffers[min_ind][pos..pos + len];
+            let (records, is_output) = (self.records, self.is_output);
+            match (self.unique, &mut self.pending) {
+                (None, _) => push_record(records, is_output, record, &mut self.out_buffer),
+                (Some(keep), Some(pending)) if records.is_duplicate(pending, record) => {
+                    records.merge_duplicate(keep, pending, record);
+                }
+                (Some(_), pending) => {
+                    if let Some(pending) = pending {
+                        push_record(records, is_output, pending, &mut self.out_buffer);
+                    }
+                    let pending = pending.get_or_insert_with(Vec::new);
+                    pending.clear();
+                    pending.extend_from_slice(record);
+                }
+            }
+            self.in_buffers_pos[min_ind] += len as u64;
+            if self.in_buffers_pos[min_ind] as usize == self.in_buffers[min_ind].len() {
+                self.load_next_buffer(loader, min_ind)?;
+            } else {
+                self.update_head(min_ind);
+            }
+            tree.replay(|a, b| self.is_before(a, b));
+            // We filled up the output buffer - write it out and clear.
+            if self.out_buffer.len() >= self.buffer_size as usize {
+                self.out_file
+                    .write_all(&self.out_buffer)
+                    .during(Phase::Merge, self.out_file_path)?;
+                written += self.out_buffer.len() as u64;
+                self.out_buffer.clear();
+            }
+        }
+        // Write out the rest.
+        if let Some(pending) = self.pending.take() {
+            push_record(self.records, self.is_output, &pending, &mut self.out_buffer);
+        }
+        self.out_file
+            .write_all(&self.out_buffer)
+            .and_then(|_| self.out_file.flush())
+            .during(Phase::Merge, self.out_file_path)?;
+        written += self.out_buffer.len() as u64;
+        self.out_buffer.clear();
+        Ok(written)
+    }
+
+    /// Loads a corresponding i-th buffer from the input files. Only whole records are kept in the
+    /// buffer, the rest of the run is read on the next call.
+    fn load_next_buffer(&mut self, loader: &mut Loader, i: usize) -> Result<(), SortError> {
+        self.in_buffers_pos[i] = 0;
+        loader.load(
+            self.records,
+            i,
+            &mut self.in_runs[i],
+            self.buffer_size,
+            &mut self.in_buffers[i],
+        )?;
+        self.update_head(i);
+        Ok(())
+    }
+
+    /// Initialized buffers.
+    fn init_buffers(&mut self, loader: &mut Loader) -> Result<(), SortError> {
+        for (i, run) in self.in_runs.iter().enumerate() {
+            loader.start(i, run, self.buffer_size);
+        }
+        for i in 0..self.in_buffers.len() {
+            self.load_next_buffer(loader, i)?;
+        }
+        self.out_buffer.clear();
+        Ok(())
+    }
+}
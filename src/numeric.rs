@@ -0,0 +1,213 @@
+//! Comparison of numbers written in keys, as done by `sort -n`, `-g` and `-h` in the C locale.
+
+use std::cmp::Ordering;
+
+/// Decimal number split into its parts, without leading zeros of the integer part and trailing
+/// zeros of the fraction.
+struct Decimal<'a> {
+    negative: bool,
+    integer: &'a [u8],
+    fraction: &'a [u8],
+    /// Length of the number in the text, including the leading blanks.
+    len: usize,
+}
+
+impl<'a> Decimal<'a> {
+    /// Parses the number at the start of `text`, after optional blanks. A text which doesn't start
+    /// with a number is zero.
+    fn parse(text: &'a [u8]) -> Self {
+        let digits = |from: usize| {
+            from + text[from..]
+                .iter()
+                .take_while(|b| b.is_ascii_digit())
+                .count()
+        };
+        let mut pos = text
+            .iter()
+            .take_while(|&&b| b == b' ' || b == b'\t')
+            .count();
+        let negative = text.get(pos) == Some(&b'-');
+        if negative {
+            pos += 1;
+        }
+        let integer_end = digits(pos);
+        let integer = &text[pos..integer_end];
+        pos = integer_end;
+        let mut fraction: &[u8] = &[];
+        if text.get(pos) == Some(&b'.') {
+            let fraction_end = digits(pos + 1);
+            fraction = &text[pos + 1..fraction_end];
+            pos = fraction_end;
+        }
+        let integer = &integer[integer.iter().take_while(|&&b| b == b'0').count()..];
+        let fraction =
+            &fraction[..fraction.len() - fraction.iter().rev().take_while(|&&b| b == b'0').count()];
+        Decimal {
+            // Zero is neither negative nor positive.
+            negative: negative && !(integer.is_empty() && fraction.is_empty()),
+            integer,
+            fraction,
+            len: pos,
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        self.integer.is_empty() && self.fraction.is_empty()
+    }
+
+    /// Compares the absolute values.
+    fn cmp_abs(&self, other: &Decimal) -> Ordering {
+        self.integer
+            .len()
+            .cmp(&other.integer.len())
+            .then_with(|| self.integer.cmp(other.integer))
+            .then_with(|| self.fraction.cmp(other.fraction))
+    }
+
+    /// Returns -1, 0 or 1 for negative numbers, zero and positive numbers respectively.
+    fn sign(&self) -> i8 {
+        match (self.negative, self.is_zero()) {
+            (true, _) => -1,
+            (false, true) => 0,
+            (false, false) => 1,
+        }
+    }
+}
+
+/// Compares integers and decimal fractions with an optional minus sign, like `sort -n`.
+pub(crate) fn cmp_numeric(a: &[u8], b: &[u8]) -> Ordering {
+    let (a, b) = (Decimal::parse(a), Decimal::parse(b));
+    a.sign().cmp(&b.sign()).then_with(|| {
+        if a.negative {
+            b.cmp_abs(&a)
+        } else {
+            a.cmp_abs(&b)
+        }
+    })
+}
+
+/// Compares numbers with an optional SI suffix, such as `2K` or `1G`, like `sort -h`. Numbers are
+/// ordered by their sign, then by the suffix, and then by the value.
+pub(crate) fn cmp_human(a: &[u8], b: &[u8]) -> Ordering {
+    let suffix = |text: &[u8], number: &Decimal| {
+        text.get(number.len)
+            // Only kilo is accepted in lower case.
+            .map(|&b| if b == b'k' { b'K' } else { b })
+            .and_then(|b| b"KMGTPEZYRQ".iter().position(|&s| s == b))
+            .map_or(0, |i| i + 1)
+    };
+    let (x, y) = (Decimal::parse(a), Decimal::parse(b));
+    x.sign().cmp(&y.sign()).then_with(|| {
+        let ord = suffix(a, &x)
+            .cmp(&suffix(b, &y))
+            .then_with(|| x.cmp_abs(&y));
+        if x.negative {
+            ord.reverse()
+        } else {
+            ord
+        }
+    })
+}
+
+/// Parses the floating point number at the start of `text`, after optional blanks. Returns `None`
+/// if the text doesn't start with a number.
+fn parse_float(text: &[u8]) -> Option<f64> {
+    let start = text
+        .iter()
+        .take_while(|&&b| b == b' ' || b == b'\t')
+        .count();
+    let text = &text[start..];
+    // The longest prefix which is a number: [sign] digits [. digits] [e [sign] digits], or an
+    // infinity or a NaN.
+    let digits = |from: usize| {
+        from + text[from..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count()
+    };
+    let mut pos = usize::from(matches!(text.first(), Some(b'-') | Some(b'+')));
+    let words: [&[u8]; 3] = [b"infinity", b"inf", b"nan"];
+    if let Some(word) = words.iter().find(|word| {
+        text.len() >= pos + word.len() && text[pos..pos + word.len()].eq_ignore_ascii_case(word)
+    }) {
+        pos += word.len();
+    } else {
+        let integer_end = digits(pos);
+        let mut end = integer_end;
+        if text.get(end) == Some(&b'.') {
+            end = digits(end + 1);
+        }
+        // A lone dot isn't a number.
+        if end - pos <= usize::from(end != integer_end) {
+            return None;
+        }
+        pos = end;
+        if matches!(text.get(pos), Some(b'e') | Some(b'E')) {
+            let exponent =
+                pos + 1 + usize::from(matches!(text.get(pos + 1), Some(b'-') | Some(b'+')));
+            let exponent_end = digits(exponent);
+            if exponent_end > exponent {
+                pos = exponent_end;
+            }
+        }
+    }
+    std::str::from_utf8(&text[..pos]).ok()?.parse().ok()
+}
+
+/// Compares floating point numbers in any notation, like `sort -g`. Texts which aren't numbers go
+/// first, then NaNs, then the numbers in ascending order.
+pub(crate) fn cmp_general(a: &[u8], b: &[u8]) -> Ordering {
+    let rank = |number: Option<f64>| match number {
+        None => 0,
+        Some(number) if number.is_nan() => 1,
+        Some(_) => 2,
+    };
+    let (x, y) = (parse_float(a), parse_float(b));
+    rank(x).cmp(&rank(y)).then_with(|| match (x, y) {
+        (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+        _ => Ordering::Equal,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_sorted(cmp: fn(&[u8], &[u8]) -> Ordering, sorted: &[&str]) -> bool {
+        sorted
+            .windows(2)
+            .all(|w| cmp(w[0].as_bytes(), w[1].as_bytes()).is_lt())
+    }
+
+    #[test]
+    fn should_compare_numbers() {
+        let sorted = [
+            "-10", "-9.5", "-9", "abc", "0.5", " 9", "10", "10.05", "10.5", "0100",
+        ];
+        assert!(is_sorted(cmp_numeric, &sorted));
+        assert_eq!(cmp_numeric(b"-0", b"0.000"), Ordering::Equal);
+        assert_eq!(cmp_numeric(b"007", b"7.0"), Ordering::Equal);
+        assert_eq!(cmp_numeric(b"", b"x"), Ordering::Equal);
+    }
+
+    #[test]
+    fn should_compare_human_sizes() {
+        let sorted = [
+            "-1G", "-2K", "-1", "0", "2", "1000", "1K", "2k", "1023M", "1G", "1.5T",
+        ];
+        assert!(is_sorted(cmp_human, &sorted));
+        assert_eq!(cmp_human(b"1K", b"1.0K"), Ordering::Equal);
+        assert_eq!(cmp_human(b"1m", b"1"), Ordering::Equal);
+    }
+
+    #[test]
+    fn should_compare_floats() {
+        let sorted = [
+            "x", "nan", "-inf", "-1e3", "-2", ".5", "1E1", "+11", "infinity",
+        ];
+        assert!(is_sorted(cmp_general, &sorted));
+        assert_eq!(cmp_general(b"5e-1x", b".5"), Ordering::Equal);
+        assert_eq!(cmp_general(b"1e", b"1"), Ordering::Equal);
+        assert_eq!(cmp_general(b".", b"-"), Ordering::Equal);
+    }
+}
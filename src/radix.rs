@@ -0,0 +1,109 @@
+//! MSD radix sort of records on fixed-width keys compared byte by byte, such as the keys of
+//! [`RecordFormat::Fixed`](crate::RecordFormat::Fixed) records or big-endian integers. Keys which
+//! have to be parsed, such as the numbers of lines, or decoded, as with codecs, aren't radix
+//! sorted.
+
+use std::ops::Range;
+
+/// Buckets of fewer records are sorted by comparison, which is faster for them.
+const SMALL_BUCKET: usize = 64;
+
+/// Sorts `records` on the bytes of `key` in ascending or descending order. Records with equal keys
+/// keep their order.
+pub(crate) fn radix_sort(records: &mut [&[u8]], key: Range<usize>, descending: bool) {
+    let mut scratch = records.to_vec();
+    sort_by_digit(records, &mut scratch, key, descending);
+}
+
+/// Sorts `records` equal on the key bytes before `key.start` by the rest of the key, using
+/// `scratch` of the same length.
+fn sort_by_digit<'r>(
+    records: &mut [&'r [u8]],
+    scratch: &mut [&'r [u8]],
+    mut key: Range<usize>,
+    descending: bool,
+) {
+    let bucket = |record: &[u8], digit: usize| {
+        let b = record[digit];
+        usize::from(if descending { u8::MAX - b } else { b })
+    };
+    while !key.is_empty() {
+        if records.len() < SMALL_BUCKET {
+            records.sort_by(|a, b| {
+                let ord = a[key.clone()].cmp(&b[key.clone()]);
+                if descending {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            });
+            return;
+        }
+        let digit = key.start;
+        key.start += 1;
+        let mut ends = [0; 256];
+        for record in records.iter() {
+            ends[bucket(record, digit)] += 1;
+        }
+        // All the records fall into one bucket - go on with the next digit.
+        if ends.contains(&records.len()) {
+            continue;
+        }
+        for i in 1..ends.len() {
+            ends[i] += ends[i - 1];
+        }
+        // Distribute the records from the back, so equal ones keep their order.
+        let mut starts = ends;
+        for record in records.iter().rev() {
+            let b = bucket(record, digit);
+            starts[b] -= 1;
+            scratch[starts[b]] = record;
+        }
+        records.copy_from_slice(scratch);
+        for (&start, &end) in starts.iter().zip(&ends) {
+            if end - start > 1 {
+                sort_by_digit(
+                    &mut records[start..end],
+                    &mut scratch[start..end],
+                    key.clone(),
+                    descending,
+                );
+            }
+        }
+        return;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_sort_on_key_bytes() {
+        let mut seed = 42u64;
+        // Keys of two bytes out of few values, so there are many equal ones, after an index.
+        let data: Vec<[u8; 4]> = (0..2000u16)
+            .map(|i| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                let [hi, lo] = i.to_be_bytes();
+                [hi, lo, (seed % 3) as u8, (seed >> 8) as u8 % 200]
+            })
+            .collect();
+        for &descending in &[false, true] {
+            let mut expected: Vec<&[u8]> = data.iter().map(|r| &r[..]).collect();
+            expected.sort_by(|a, b| {
+                let ord = a[2..].cmp(&b[2..]);
+                if descending {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            });
+            let mut sorted: Vec<&[u8]> = data.iter().map(|r| &r[..]).collect();
+            radix_sort(&mut sorted, 2..4, descending);
+            assert_eq!(sorted, expected);
+        }
+    }
+}
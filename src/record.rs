@@ -0,0 +1,811 @@
+use crate::compare::Compare;
+use crate::radix::radix_sort;
+use crate::Collation;
+use std::cmp::Ordering;
+use std::io::{Error, Write};
+use std::mem;
+use std::ops::Range;
+use std::slice;
+
+/// Describes how the content of a file is split into records, i.e. into the units being sorted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// Every byte is a record on its own.
+    #[default]
+    Bytes,
+    /// Every `\n`-terminated line is a record. Lines are compared without the terminator, so the
+    /// output matches `sort` under `LC_ALL=C`. A missing terminator on the last line is added.
+    Lines,
+    /// Every `size` bytes are a record, compared on the `key_len` bytes starting at `key_offset`,
+    /// e.g. 100-byte records with a 10-byte key at the start as in the sortbenchmark.org format.
+    /// The input must consist of whole records. Records with equal keys are compared as a whole,
+    /// unless the sort is stable. Unless a comparator or keys are set, the records are radix
+    /// sorted on the key, which also suits big-endian integers. That's the only radix sorted
+    /// format: numeric keys of lines and records of a [`RecordCodec`] are sorted by comparison, as
+    /// their bytes don't go in the order of their values.
+    Fixed {
+        size: usize,
+        key_offset: usize,
+        key_len: usize,
+    },
+}
+
+impl RecordFormat {
+    /// Checks that the format describes records which can be split.
+    pub(crate) fn validate(self) -> Result<(), String> {
+        match self {
+            RecordFormat::Fixed { size: 0, .. } => Err("record size must not be zero".into()),
+            RecordFormat::Fixed {
+                size,
+                key_offset,
+                key_len,
+            } if key_offset.checked_add(key_len).is_none_or(|end| end > size) => Err(format!(
+                "key of {} bytes at offset {} doesn't fit into a record of {} bytes",
+                key_len, key_offset, size
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the length (including the terminator) of the first record in `buf`, or `None` if
+    /// `buf` doesn't contain a complete record.
+    pub(crate) fn record_len(self, buf: &[u8]) -> Option<usize> {
+        match self {
+            RecordFormat::Bytes => buf.first().map(|_| 1),
+            RecordFormat::Lines => buf.iter().position(|&b| b == b'\n').map(|i| i + 1),
+            RecordFormat::Fixed { size, .. } => Some(size).filter(|&size| buf.len() >= size),
+        }
+    }
+
+    /// Returns the length of the longest prefix of `buf` consisting of whole records. When `eof` is
+    /// set, the trailing unterminated line (if any) is considered whole as well, while a trailing
+    /// part of a fixed size record is malformed.
+    pub(crate) fn complete_len(self, buf: &[u8], eof: bool) -> Result<usize, Malformed> {
+        Ok(match self {
+            RecordFormat::Bytes => buf.len(),
+            RecordFormat::Lines if eof => buf.len(),
+            RecordFormat::Lines => buf.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1),
+            RecordFormat::Fixed { size, .. } => {
+                let len = buf.len() - buf.len() % size;
+                if eof && len != buf.len() {
+                    return Err(Malformed {
+                        pos: len,
+                        reason: format!(
+                            "truncated record of {} bytes, expected {}",
+                            buf.len() - len,
+                            size
+                        ),
+                    });
+                }
+                len
+            }
+        })
+    }
+
+    /// Rounds `size` down to whole records, keeping at least one.
+    pub(crate) fn align(self, size: usize) -> usize {
+        match self {
+            RecordFormat::Fixed { size: record, .. } => (size - size % record).max(record),
+            _ => size,
+        }
+    }
+
+    /// Returns the part of the record which takes part in comparison.
+    pub(crate) fn key(self, record: &[u8]) -> &[u8] {
+        match self {
+            RecordFormat::Bytes => record,
+            RecordFormat::Lines => record.strip_suffix(b"\n").unwrap_or(record),
+            RecordFormat::Fixed {
+                key_offset,
+                key_len,
+                ..
+            } => &record[key_offset..key_offset + key_len],
+        }
+    }
+
+    /// Compares two whole records on their keys. Fixed size records with equal keys are compared
+    /// as a whole unless equal ones keep their order, so their order doesn't depend on how the
+    /// input is split into runs.
+    pub(crate) fn cmp(self, compare: &Compare, a: &[u8], b: &[u8]) -> Ordering {
+        let ord = compare.cmp(self.key(a), self.key(b));
+        match self {
+            RecordFormat::Fixed { .. } if !compare.is_stable() => ord.then_with(|| {
+                let ord = a.cmp(b);
+                if compare.is_reverse() {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            }),
+            _ => ord,
+        }
+    }
+
+    /// Returns the memory [`RecordFormat::sort_chunk`] takes for the whole records in `buf`,
+    /// besides the records themselves.
+    pub(crate) fn sort_memory(self, compare: &Compare, buf: &[u8]) -> usize {
+        let records = match self {
+            // Bytes are sorted in place, but the stable sort takes a copy of them.
+            RecordFormat::Bytes if compare.is_natural() || !compare.is_stable() => return 0,
+            RecordFormat::Bytes => return buf.len(),
+            RecordFormat::Lines => count_lines(buf),
+            RecordFormat::Fixed { size, .. } => buf.len() / size,
+        };
+        // Radix and stable sorts take a copy of the records index.
+        let copies = match self {
+            RecordFormat::Fixed { .. } if !compare.is_custom() => 2,
+            _ if compare.is_stable() => 2,
+            _ => 1,
+        };
+        records * copies * mem::size_of::<&[u8]>()
+    }
+
+    /// Sorts a chunk of whole records and writes it out. With `unique` set, only one of equal
+    /// records is written. Returns the number of bytes written.
+    pub(crate) fn sort_chunk<W: Write>(
+        self,
+        compare: &Compare,
+        unique: Option<Keep>,
+        chunk: &mut [u8],
+        out: &mut W,
+    ) -> Result<u64, Error> {
+        let mut records: Vec<&[u8]> = match self {
+            RecordFormat::Bytes => {
+                if compare.is_natural() {
+                    chunk.sort_unstable();
+                } else {
+                    sort_by(chunk, compare.is_stable(), |a, b| {
+                        compare.cmp(slice::from_ref(a), slice::from_ref(b))
+                    });
+                }
+                if unique.is_some() {
+                    let is_duplicate = |a: &u8, b: &u8| {
+                        compare
+                            .cmp_keys(slice::from_ref(a), slice::from_ref(b))
+                            .is_eq()
+                    };
+                    let mut written = 0;
+                    for (i, b) in chunk.iter().enumerate() {
+                        if !is_dropped(unique, chunk, i, is_duplicate) {
+                            out.write_all(slice::from_ref(b))?;
+                            written += 1;
+                        }
+                    }
+                    return Ok(written);
+                }
+                out.write_all(chunk)?;
+                return Ok(chunk.len() as u64);
+            }
+            RecordFormat::Lines => {
+                if chunk.is_empty() {
+                    return Ok(0);
+                }
+                let chunk = chunk.strip_suffix(b"\n").unwrap_or(chunk);
+                chunk.split(|&b| b == b'\n').collect()
+            }
+            RecordFormat::Fixed { size, .. } => chunk.chunks_exact(size).collect(),
+        };
+        // Lines are split without the terminators, so they are compared as they are.
+        let key = |record| match self {
+            RecordFormat::Fixed { .. } => self.key(record),
+            _ => record,
+        };
+        match self {
+            // Radix sort keeps equal keys in order, so it suits the stable order as well.
+            RecordFormat::Fixed {
+                key_offset,
+                key_len,
+                ..
+            } if !compare.is_custom() => {
+                let key = key_offset..key_offset + key_len;
+                radix_sort(&mut records, key.clone(), compare.is_reverse());
+                if !compare.is_stable() {
+                    for equal in records.chunk_by_mut(|a, b| a[key.clone()] == b[key.clone()]) {
+                        equal.sort_unstable_by(|a, b| self.cmp(compare, a, b));
+                    }
+                }
+            }
+            RecordFormat::Lines if compare.is_natural() => records.sort_unstable(),
+            _ => sort_by(&mut records, compare.is_stable(), |a, b| {
+                self.cmp(compare, a, b)
+            }),
+        }
+        let terminator: &[u8] = if self == RecordFormat::Lines {
+            b"\n"
+        } else {
+            b""
+        };
+        let mut written = 0;
+        for (i, record) in records.iter().enumerate() {
+            if is_dropped(unique, &records, i, |a, b| {
+                compare.cmp_keys(key(*a), key(*b)).is_eq()
+            }) {
+                continue;
+            }
+            out.write_all(record)?;
+            out.write_all(terminator)?;
+            written += (record.len() + terminator.len()) as u64;
+        }
+        Ok(written)
+    }
+}
+
+/// Counts the lines in `buf`, including an unterminated last one.
+fn count_lines(buf: &[u8]) -> usize {
+    buf.iter().filter(|&&b| b == b'\n').count()
+        + usize::from(buf.last().is_some_and(|&b| b != b'\n'))
+}
+
+/// Sorts `items`, keeping equal ones in their order if `stable` is set.
+fn sort_by<T, C>(items: &mut [T], stable: bool, cmp: C)
+where
+    C: FnMut(&T, &T) -> Ordering,
+{
+    if stable {
+        items.sort_by(cmp);
+    } else {
+        items.sort_unstable_by(cmp);
+    }
+}
+
+/// Which of equal records the unique mode keeps, in the order of the input.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Keep {
+    /// The record read first.
+    #[default]
+    First,
+    /// The record read last.
+    Last,
+}
+
+/// Tells whether the i-th of sorted `items` is dropped in the `unique` mode, given which of
+/// duplicates are kept.
+fn is_dropped<T, D>(unique: Option<Keep>, items: &[T], i: usize, is_duplicate: D) -> bool
+where
+    D: Fn(&T, &T) -> bool,
+{
+    match unique {
+        None => false,
+        Some(Keep::First) => i != 0 && is_duplicate(&items[i - 1], &items[i]),
+        Some(Keep::Last) => i + 1 < items.len() && is_duplicate(&items[i], &items[i + 1]),
+    }
+}
+
+/// Converts records of any ordered type to and from bytes, so they can be sorted externally without
+/// turning them into text first. The input, the runs and the output hold the encoded records one
+/// after another.
+///
+/// ```
+/// use big_file_sort::RecordCodec;
+/// use std::convert::TryInto;
+///
+/// /// `(user_id, ts)` pairs stored as 16 little-endian bytes.
+/// struct Visits;
+///
+/// impl RecordCodec for Visits {
+///     type Record = (u64, i64);
+///
+///     fn encode(&self, &(user_id, ts): &(u64, i64), buf: &mut Vec<u8>) {
+///         buf.extend_from_slice(&user_id.to_le_bytes());
+///         buf.extend_from_slice(&ts.to_le_bytes());
+///     }
+///
+///     fn decode(&self, buf: &[u8]) -> Result<Option<((u64, i64), usize)>, String> {
+///         if buf.len() < 16 {
+///             return Ok(None);
+///         }
+///         let user_id = u64::from_le_bytes(buf[..8].try_into().unwrap());
+///         let ts = i64::from_le_bytes(buf[8..16].try_into().unwrap());
+///         Ok(Some(((user_id, ts), 16)))
+///     }
+/// }
+/// ```
+pub trait RecordCodec: Sync {
+    /// Type of the records.
+    type Record: Ord;
+
+    /// Appends the encoded `record` to `buf`.
+    fn encode(&self, record: &Self::Record, buf: &mut Vec<u8>);
+
+    /// Decodes the record at the start of `buf`. Returns the record and the length of its
+    /// encoding, which must not be zero, or `None` if `buf` holds only a part of the record. The
+    /// records of the input which don't decode back from their encoding are malformed.
+    fn decode(&self, buf: &[u8]) -> Result<Option<(Self::Record, usize)>, String>;
+}
+
+/// Record which can't be decoded, found `pos` bytes into a chunk.
+#[derive(Debug)]
+pub(crate) struct Malformed {
+    pub(crate) pos: usize,
+    pub(crate) reason: String,
+}
+
+/// How records are split, sorted and compared while being sorted. Unless told otherwise by
+/// [`Records::has_run_keys`], records stay encoded the way they are stored in the input, the runs
+/// and the output.
+pub(crate) trait Records: Sync {
+    /// Returns the length of the first record in `buf`, which holds whole records only.
+    fn record_len(&self, buf: &[u8]) -> Option<usize>;
+
+    /// Returns the length of the longest prefix of `buf` consisting of whole records. When `eof`
+    /// is set, the rest of `buf` must be a whole record as well.
+    fn complete_len(&self, buf: &[u8], eof: bool) -> Result<usize, Malformed>;
+
+    /// Rounds the size of a buffer down to whole records, when their size is known in advance.
+    fn align(&self, size: usize) -> usize {
+        size
+    }
+
+    /// Returns the length of the longest prefix of `buf` consisting of whole records, as they are
+    /// stored in the runs.
+    fn run_len(&self, buf: &[u8]) -> usize {
+        let mut len = 0;
+        while let Some(record_len) = self.record_len(&buf[len..]) {
+            len += record_len;
+        }
+        len
+    }
+
+    /// Returns the length of the first record of the input in `buf`, or `None` if it lacks a
+    /// terminator. The input of most records is stored in the runs as it is.
+    fn input_len(&self, buf: &[u8]) -> Option<usize> {
+        self.record_len(buf)
+    }
+
+    /// Compares two whole records of the input.
+    fn cmp_input(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.cmp(a, b)
+    }
+
+    /// Tells whether the runs store more than the records, so they can't be output as they are.
+    fn has_run_keys(&self) -> bool {
+        false
+    }
+
+    /// Appends the record as it is output to `out`, given as it is stored in the runs.
+    fn write_output(&self, record: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(record);
+    }
+
+    /// Merges a duplicate `record` of the runs into the `kept` one, in the unique mode.
+    fn merge_duplicate(&self, keep: Keep, kept: &mut Vec<u8>, record: &[u8]) {
+        if keep == Keep::Last {
+            kept.clear();
+            kept.extend_from_slice(record);
+        }
+    }
+
+    /// Compares two whole records.
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering;
+
+    /// Tells whether two whole records are equal, so only one of them is kept in the unique mode.
+    fn is_duplicate(&self, a: &[u8], b: &[u8]) -> bool;
+
+    /// Tells whether equal records keep their order, rather than being interchangeable.
+    fn is_stable(&self) -> bool;
+
+    /// Returns the memory [`Records::sort_chunk`] takes for the whole records of the input in
+    /// `buf`, besides the records themselves, e.g. for an index of them. Chunks are cut so that
+    /// both fit into the memory budget.
+    fn sort_memory(&self, buf: &[u8]) -> usize;
+
+    /// Sorts a chunk of whole records and writes it out. With `unique` set, only one of equal
+    /// records is written. Returns the number of bytes written.
+    fn sort_chunk<W: Write>(
+        &self,
+        unique: Option<Keep>,
+        chunk: &mut [u8],
+        out: &mut W,
+    ) -> Result<u64, Error>;
+}
+
+/// Records of a [`RecordFormat`] in the order of a comparator.
+pub(crate) struct FormatRecords {
+    pub(crate) format: RecordFormat,
+    pub(crate) compare: Compare,
+}
+
+impl Records for FormatRecords {
+    fn record_len(&self, buf: &[u8]) -> Option<usize> {
+        self.format.record_len(buf)
+    }
+
+    fn complete_len(&self, buf: &[u8], eof: bool) -> Result<usize, Malformed> {
+        self.format.complete_len(buf, eof)
+    }
+
+    fn align(&self, size: usize) -> usize {
+        self.format.align(size)
+    }
+
+    fn run_len(&self, buf: &[u8]) -> usize {
+        self.format
+            .complete_len(buf, false)
+            .expect("only the end of the input may be malformed; qed")
+    }
+
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.format.cmp(&self.compare, a, b)
+    }
+
+    fn is_duplicate(&self, a: &[u8], b: &[u8]) -> bool {
+        self.compare
+            .cmp_keys(self.format.key(a), self.format.key(b))
+            .is_eq()
+    }
+
+    fn is_stable(&self) -> bool {
+        self.compare.is_stable()
+    }
+
+    fn sort_memory(&self, buf: &[u8]) -> usize {
+        self.format.sort_memory(&self.compare, buf)
+    }
+
+    fn sort_chunk<W: Write>(
+        &self,
+        unique: Option<Keep>,
+        chunk: &mut [u8],
+        out: &mut W,
+    ) -> Result<u64, Error> {
+        self.format.sort_chunk(&self.compare, unique, chunk, out)
+    }
+}
+
+/// Records of a [`RecordCodec`], decoded for sorting and comparison.
+pub(crate) struct CodecRecords<C> {
+    pub(crate) codec: C,
+    /// Whether equal records keep their order.
+    pub(crate) stable: bool,
+}
+
+impl<C: RecordCodec> CodecRecords<C> {
+    /// Decodes a record known to be whole.
+    fn decode_whole(&self, buf: &[u8]) -> (C::Record, usize) {
+        match self.codec.decode(buf) {
+            Ok(Some(decoded)) => decoded,
+            _ => panic!("records of the input are checked to decode back from their encoding; qed"),
+        }
+    }
+
+    /// Tells whether `record` decodes back from its encoding, so it can be stored in the runs.
+    fn round_trips(&self, record: &C::Record, encoded: &mut Vec<u8>) -> bool {
+        encoded.clear();
+        self.codec.encode(record, encoded);
+        match self.codec.decode(encoded) {
+            Ok(Some((decoded, len))) => len == encoded.len() && decoded == *record,
+            _ => false,
+        }
+    }
+}
+
+impl<C: RecordCodec> Records for CodecRecords<C> {
+    fn record_len(&self, buf: &[u8]) -> Option<usize> {
+        if buf.is_empty() {
+            return None;
+        }
+        Some(self.decode_whole(buf).1)
+    }
+
+    fn complete_len(&self, buf: &[u8], eof: bool) -> Result<usize, Malformed> {
+        let mut pos = 0;
+        let mut encoded = Vec::new();
+        while pos < buf.len() {
+            match self.codec.decode(&buf[pos..]) {
+                Ok(Some((record, len))) if len != 0 && self.round_trips(&record, &mut encoded) => {
+                    pos += len
+                }
+                Ok(Some((_, 0))) => {
+                    return Err(Malformed {
+                        pos,
+                        reason: "record decoded from no bytes".into(),
+                    })
+                }
+                Ok(Some(_)) => {
+                    return Err(Malformed {
+                        pos,
+                        reason: "record doesn't decode back from its encoding".into(),
+                    })
+                }
+                Ok(None) if eof => {
+                    return Err(Malformed {
+                        pos,
+                        reason: "truncated record at the end of the input".into(),
+                    })
+                }
+                Ok(None) => break,
+                Err(reason) => return Err(Malformed { pos, reason }),
+            }
+        }
+        Ok(pos)
+    }
+
+    fn run_len(&self, buf: &[u8]) -> usize {
+        self.complete_len(buf, false)
+            .expect("runs consist of valid records; qed")
+    }
+
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.decode_whole(a).0.cmp(&self.decode_whole(b).0)
+    }
+
+    fn is_duplicate(&self, a: &[u8], b: &[u8]) -> bool {
+        self.cmp(a, b).is_eq()
+    }
+
+    fn is_stable(&self) -> bool {
+        self.stable
+    }
+
+    fn sort_memory(&self, buf: &[u8]) -> usize {
+        let mut records = 0;
+        let mut pos = 0;
+        while pos < buf.len() {
+            pos += self.decode_whole(&buf[pos..]).1;
+            records += 1;
+        }
+        // The decoded records, a copy of them for the stable sort, and the encoded ones.
+        let copies = if self.stable { 2 } else { 1 };
+        records * copies * mem::size_of::<C::Record>() + buf.len()
+    }
+
+    fn sort_chunk<W: Write>(
+        &self,
+        unique: Option<Keep>,
+        chunk: &mut [u8],
+        out: &mut W,
+    ) -> Result<u64, Error> {
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while pos < chunk.len() {
+            let (record, len) = self.decode_whole(&chunk[pos..]);
+            records.push(record);
+            pos += len;
+        }
+        sort_by(&mut records, self.stable, Ord::cmp);
+        let mut encoded = Vec::with_capacity(chunk.len());
+        for (i, record) in records.iter().enumerate() {
+            if !is_dropped(unique, &records, i, PartialEq::eq) {
+                self.codec.encode(record, &mut encoded);
+            }
+        }
+        out.write_all(&encoded)?;
+        Ok(encoded.len() as u64)
+    }
+}
+
+/// Lines in the order of a [`Collation`]. The runs store every line after its sort key, as the
+/// 4-byte big-endian length of the key, the key and the line, so they are merged by comparing the
+/// keys byte by byte. Lines with equal keys are compared byte by byte.
+pub(crate) struct CollatedRecords {
+    pub(crate) collation: Collation,
+    pub(crate) reverse: bool,
+    /// Whether lines with equal sort keys keep their order, instead of being compared byte by
+    /// byte.
+    pub(crate) stable: bool,
+    /// Most bytes the sort keys take for every byte of the lines, see
+    /// [`Collation::key_expansion`].
+    pub(crate) key_expansion: usize,
+}
+
+/// Decodes the length of a sort key stored in a run.
+fn key_len(len: &[u8]) -> usize {
+    u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize
+}
+
+impl CollatedRecords {
+    /// Splits a record of a run into the sort key and the line.
+    fn split<'r>(&self, record: &'r [u8]) -> (&'r [u8], &'r [u8]) {
+        let (len, rest) = record.split_at(4);
+        rest.split_at(key_len(len))
+    }
+
+    /// Returns the most bytes the sort keys of `lines` lines in `buf` take.
+    fn keys_capacity(&self, buf: &[u8], lines: usize) -> usize {
+        buf.len() * self.key_expansion + 2 * lines
+    }
+
+    fn cmp_keyed(&self, (a_key, a): (&[u8], &[u8]), (b_key, b): (&[u8], &[u8])) -> Ordering {
+        let mut ord = a_key.cmp(b_key);
+        if !self.stable {
+            ord = ord.then_with(|| a.cmp(b));
+        }
+        if self.reverse {
+            ord.reverse()
+        } else {
+            ord
+        }
+    }
+}
+
+impl Records for CollatedRecords {
+    fn record_len(&self, buf: &[u8]) -> Option<usize> {
+        let line = 4 + key_len(buf.get(..4)?);
+        let line_len = buf.get(line..)?.iter().position(|&b| b == b'\n')? + 1;
+        Some(line + line_len)
+    }
+
+    fn complete_len(&self, buf: &[u8], eof: bool) -> Result<usize, Malformed> {
+        RecordFormat::Lines.complete_len(buf, eof)
+    }
+
+    fn input_len(&self, buf: &[u8]) -> Option<usize> {
+        RecordFormat::Lines.record_len(buf)
+    }
+
+    fn cmp_input(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let (a, b) = (RecordFormat::Lines.key(a), RecordFormat::Lines.key(b));
+        let (mut a_key, mut b_key) = (Vec::new(), Vec::new());
+        self.collation.sort_key(a, &mut a_key);
+        self.collation.sort_key(b, &mut b_key);
+        self.cmp_keyed((&a_key, a), (&b_key, b))
+    }
+
+    fn has_run_keys(&self) -> bool {
+        true
+    }
+
+    fn write_output(&self, record: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(self.split(record).1);
+    }
+
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.cmp_keyed(self.split(a), self.split(b))
+    }
+
+    fn is_duplicate(&self, a: &[u8], b: &[u8]) -> bool {
+        self.split(a).0 == self.split(b).0
+    }
+
+    fn is_stable(&self) -> bool {
+        self.stable
+    }
+
+    fn sort_memory(&self, buf: &[u8]) -> usize {
+        let lines = count_lines(buf);
+        let copies = if self.stable { 2 } else { 1 };
+        lines * copies * mem::size_of::<(Range<usize>, &[u8])>() + self.keys_capacity(buf, lines)
+    }
+
+    fn sort_chunk<W: Write>(
+        &self,
+        unique: Option<Keep>,
+        chunk: &mut [u8],
+        out: &mut W,
+    ) -> Result<u64, Error> {
+        if chunk.is_empty() {
+            return Ok(0);
+        }
+        // Room for the longest keys, so they take no more than counted by the sort memory.
+        let count = count_lines(chunk);
+        let mut keys = Vec::with_capacity(self.keys_capacity(chunk, count));
+        let mut lines = Vec::with_capacity(count);
+        let chunk = chunk.strip_suffix(b"\n").unwrap_or(chunk);
+        for line in chunk.split(|&b| b == b'\n') {
+            let start = keys.len();
+            self.collation.sort_key(line, &mut keys);
+            lines.push((start..keys.len(), line));
+        }
+        sort_by(&mut lines, self.stable, |(a_key, a), (b_key, b)| {
+            self.cmp_keyed((&keys[a_key.clone()], a), (&keys[b_key.clone()], b))
+        });
+        let mut written = 0;
+        let is_duplicate = |(a, _): &(Range<usize>, &[u8]), (b, _): &(Range<usize>, &[u8])| {
+            keys[a.clone()] == keys[b.clone()]
+        };
+        for (i, (key, line)) in lines.iter().enumerate() {
+            if is_dropped(unique, &lines, i, is_duplicate) {
+                continue;
+            }
+            let key = &keys[key.clone()];
+            out.write_all(&(key.len() as u32).to_be_bytes())?;
+            out.write_all(key)?;
+            out.write_all(line)?;
+            out.write_all(b"\n")?;
+            written += (4 + key.len() + line.len() + 1) as u64;
+        }
+        Ok(written)
+    }
+}
+
+/// Records of another kind, each of them stored in the runs once with the number of its
+/// occurrences, as the 8-byte big-endian count followed by the record. Records are output after
+/// their counts, like `uniq -c` does.
+pub(crate) struct Counted<'a, F>(pub(crate) &'a F);
+
+/// Decodes the count of a record stored in a run.
+fn count(record: &[u8]) -> u64 {
+    let mut count = [0; 8];
+    count.copy_from_slice(&record[..8]);
+    u64::from_be_bytes(count)
+}
+
+impl<F: Records> Records for Counted<'_, F> {
+    fn record_len(&self, buf: &[u8]) -> Option<usize> {
+        Some(8 + self.0.record_len(buf.get(8..)?)?)
+    }
+
+    fn complete_len(&self, buf: &[u8], eof: bool) -> Result<usize, Malformed> {
+        self.0.complete_len(buf, eof)
+    }
+
+    fn align(&self, size: usize) -> usize {
+        self.0.align(size)
+    }
+
+    fn input_len(&self, buf: &[u8]) -> Option<usize> {
+        self.0.input_len(buf)
+    }
+
+    fn cmp_input(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.0.cmp_input(a, b)
+    }
+
+    fn has_run_keys(&self) -> bool {
+        true
+    }
+
+    fn write_output(&self, record: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(format!("{:>7} ", count(record)).as_bytes());
+        self.0.write_output(&record[8..], out);
+    }
+
+    fn merge_duplicate(&self, keep: Keep, kept: &mut Vec<u8>, record: &[u8]) {
+        let total = count(kept) + count(record);
+        if keep == Keep::Last {
+            kept.clear();
+            kept.extend_from_slice(record);
+        }
+        kept[..8].copy_from_slice(&total.to_be_bytes());
+    }
+
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.0.cmp(&a[8..], &b[8..])
+    }
+
+    fn is_duplicate(&self, a: &[u8], b: &[u8]) -> bool {
+        self.0.is_duplicate(&a[8..], &b[8..])
+    }
+
+    fn is_stable(&self) -> bool {
+        self.0.is_stable()
+    }
+
+    fn sort_memory(&self, buf: &[u8]) -> usize {
+        // The records sorted by the inner kind are buffered to count them.
+        self.0.sort_memory(buf) + buf.len()
+    }
+
+    fn sort_chunk<W: Write>(
+        &self,
+        unique: Option<Keep>,
+        chunk: &mut [u8],
+        out: &mut W,
+    ) -> Result<u64, Error> {
+        let mut sorted = Vec::with_capacity(chunk.len());
+        self.0.sort_chunk(None, chunk, &mut sorted)?;
+        let keep = unique.unwrap_or_default();
+        let record_at = |pos: usize| {
+            self.0
+                .record_len(&sorted[pos..])
+                .map(|len| &sorted[pos..pos + len])
+        };
+        let mut written = 0;
+        let mut pos = 0;
+        while let Some(first) = record_at(pos) {
+            let (mut kept, mut count) = (first, 1u64);
+            pos += first.len();
+            while let Some(record) = record_at(pos).filter(|r| self.0.is_duplicate(first, r)) {
+                if keep == Keep::Last {
+                    kept = record;
+                }
+                count += 1;
+                pos += record.len();
+            }
+            out.write_all(&count.to_be_bytes())?;
+            out.write_all(kept)?;
+            written += 8 + kept.len() as u64;
+        }
+        Ok(written)
+    }
+}
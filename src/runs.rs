@@ -0,0 +1,698 @@
+use crate::error::IoResultExt;
+use crate::merge::Run;
+use crate::record::Records;
+use crate::temp::TempFile;
+use crate::{Keep, Phase, SortError};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Error, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc::channel;
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+
+/// How the input is split into sorted runs before they are merged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RunStrategy {
+    /// Chunks of the input filling the memory budget are sorted one by one, so the runs are as
+    /// long as the memory.
+    #[default]
+    Chunks,
+    /// Records are kept in a heap, and the least one that goes after the last record of the
+    /// current run is moved to it, making room for the next record of the input. Runs are twice
+    /// as long as the memory on average, and sorted input makes a single run. Works on a single
+    /// thread.
+    ReplacementSelection,
+}
+
+/// Length of the first read into an empty chunk, which tells how much memory sorting its records
+/// takes before the rest of the chunk is read.
+const PROBE_SIZE: usize = 64 << 10;
+
+/// Reads the input in chunks of whole records.
+pub(crate) struct ChunkReader<'a, F, R> {
+    pub(crate) records: &'a F,
+    input: R,
+    input_path: &'a Path,
+    chunk_size: usize,
+    bytes_read: u64,
+    eof: bool,
+    /// Phase the read errors are tagged with.
+    phase: Phase,
+}
+
+impl<'a, F: Records, R: Read> ChunkReader<'a, F, R> {
+    pub(crate) fn new(records: &'a F, input: R, input_path: &'a Path, chunk_size: usize) -> Self {
+        ChunkReader {
+            records,
+            input,
+            input_path,
+            chunk_size: records.align(chunk_size.max(1)),
+            bytes_read: 0,
+            eof: false,
+            phase: Phase::RunGeneration,
+        }
+    }
+
+    /// Tags the read errors with `phase` instead of run generation.
+    pub(crate) fn phase(mut self, phase: Phase) -> Self {
+        self.phase = phase;
+        self
+    }
+
+    /// Returns the number of bytes read so far.
+    pub(crate) fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Fills up `chunk`, keeping the rest left in it from the previous chunk. Returns the length
+    /// of the whole records at the start of the chunk which fit into the chunk size together with
+    /// the memory sorting them takes, or `None` once the input is exhausted. At least one record
+    /// is returned, however long it is.
+    pub(crate) fn next_chunk(&mut self, chunk: &mut Vec<u8>) -> Result<Option<usize>, SortError> {
+        let mut complete = self.complete_len(chunk, 0)?;
+        let mut memory = self.records.sort_memory(&chunk[..complete]);
+        let mut limit = self.chunk_size;
+        loop {
+            // Read in steps, so the records fill up the chunk along with their sorting memory:
+            // the first step tells how much memory the records take relative to their length. A
+            // record longer than that is read in steps as long as its part already read.
+            while !self.eof && chunk.len() + memory < limit {
+                let rest = limit - chunk.len() - memory;
+                let step = if complete == 0 {
+                    PROBE_SIZE.max(chunk.len())
+                } else {
+                    (rest as f64 * complete as f64 / (complete + memory) as f64) as usize
+                };
+                if step <= limit / 64 && complete != 0 {
+                    break;
+                }
+                self.read(chunk, step.clamp(1, rest))?;
+                let len = self.complete_len(chunk, complete)?;
+                memory += self.records.sort_memory(&chunk[complete..len]);
+                complete = len;
+            }
+            if chunk.is_empty() {
+                return Ok(None);
+            }
+            if complete != 0 {
+                return Ok(Some(self.fitting_len(&chunk[..complete], limit)));
+            }
+            // A record is longer than the chunk - read more until we have it whole.
+            limit = chunk.len() + self.chunk_size;
+        }
+    }
+
+    /// Reads `len` more bytes into `chunk`, or less at the end of the input.
+    fn read(&mut self, chunk: &mut Vec<u8>, len: usize) -> Result<(), SortError> {
+        let target = chunk.len() + len;
+        chunk.reserve_exact(len);
+        while !self.eof && chunk.len() < target {
+            let len = chunk.len();
+            chunk.resize(target, 0);
+            let n = self.input.read(&mut chunk[len..]);
+            chunk.truncate(len + *n.as_ref().unwrap_or(&0));
+            let n = n.during(self.phase, self.input_path)?;
+            self.eof = n == 0;
+            self.bytes_read += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Returns the length of the whole records in `chunk`, which starts with `complete` bytes of
+    /// them.
+    fn complete_len(&self, chunk: &[u8], complete: usize) -> Result<usize, SortError> {
+        let len = self
+            .records
+            .complete_len(&chunk[complete..], self.eof)
+            .map_err(|e| SortError::MalformedRecord {
+                offset: self.bytes_read - (chunk.len() - complete - e.pos) as u64,
+                reason: e.reason,
+            })?;
+        Ok(complete + len)
+    }
+
+    /// Returns the length of the records at the start of `records` which fit into `limit` along
+    /// with their sorting memory, keeping at least one.
+    fn fitting_len(&self, records: &[u8], limit: usize) -> usize {
+        if records.len() + self.records.sort_memory(records) <= limit {
+            return records.len();
+        }
+        let mut len = 0;
+        let mut used = 0;
+        while len < records.len() {
+            let rest = &records[len..];
+            let record = self.records.input_len(rest).unwrap_or(rest.len());
+            used += record + self.records.sort_memory(&rest[..record]);
+            if used > limit && len != 0 {
+                break;
+            }
+            len += record;
+        }
+        len
+    }
+}
+
+/// Writes sorted runs, spreading them over the temporary files round-robin.
+pub(crate) struct RunWriter<'a> {
+    files: Vec<(BufWriter<&'a mut File>, &'a Path)>,
+    files_len: Vec<u64>,
+    runs: Vec<Run>,
+    /// Length of the run being written.
+    run_len: u64,
+}
+
+impl<'a> RunWriter<'a> {
+    pub(crate) fn new(tmp: &'a mut [TempFile]) -> Self {
+        let files: Vec<_> = tmp
+            .iter_mut()
+            .map(|tmp| (BufWriter::new(&mut tmp.file), tmp.path.as_path()))
+            .collect();
+        RunWriter {
+            files_len: vec![0; files.len()],
+            files,
+            runs: Vec::new(),
+            run_len: 0,
+        }
+    }
+
+    /// Writes the next run with `write`, which returns the length of the run. With `join` set, the
+    /// run becomes a part of the last one instead, and goes to the same file.
+    pub(crate) fn write_run<F>(&mut self, join: Option<Join>, write: F) -> Result<(), SortError>
+    where
+        F: FnOnce(&mut BufWriter<&'a mut File>) -> Result<u64, Error>,
+    {
+        let i = match (self.runs.last(), join) {
+            (Some(run), Some(_)) => run.file,
+            _ => self.runs.len() % self.files.len(),
+        };
+        let (file, path) = &mut self.files[i];
+        let run_len = write(file).during(Phase::RunGeneration, path)?;
+        let (run, join) = match (self.runs.last_mut(), join) {
+            (Some(run), Some(join)) if run_len != 0 => (run, join),
+            _ => {
+                self.run_len += run_len;
+                self.end_run();
+                return Ok(());
+            }
+        };
+        let start = self.files_len[i];
+        self.files_len[i] += run_len;
+        match join {
+            Join::After => {
+                debug_assert!(
+                    run.next.is_empty() && run.range.end == start,
+                    "ascending runs are only joined after ascending ones; qed"
+                );
+                run.range.end += run_len;
+            }
+            Join::Before => {
+                let range = mem::replace(&mut run.range, start..start + run_len);
+                run.next.push(range);
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends `data` to the run being written.
+    fn append(&mut self, data: &[u8]) -> Result<(), SortError> {
+        let (file, path) = self.current();
+        file.write_all(data).during(Phase::RunGeneration, path)?;
+        self.run_len += data.len() as u64;
+        Ok(())
+    }
+
+    /// Finishes the run being written, unless it's empty.
+    fn end_run(&mut self) {
+        if self.run_len != 0 {
+            let i = self.runs.len() % self.files.len();
+            let start = self.files_len[i];
+            self.runs.push(Run::new(i, start..start + self.run_len));
+            self.files_len[i] += self.run_len;
+            self.run_len = 0;
+        }
+    }
+
+    /// Returns the file of the run being written along with its path.
+    fn current(&mut self) -> (&mut BufWriter<&'a mut File>, &'a Path) {
+        let i = self.runs.len() % self.files.len();
+        let (file, path) = &mut self.files[i];
+        (file, path)
+    }
+
+    /// Flushes the files and returns the written runs.
+    pub(crate) fn finish(mut self) -> Result<Vec<Run>, SortError> {
+        for (file, path) in &mut self.files {
+            file.flush().during(Phase::RunGeneration, path)?;
+        }
+        Ok(self.runs)
+    }
+}
+
+/// How a sorted chunk continues the run written before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Join {
+    /// The chunk goes after the run, as the input ascends.
+    After,
+    /// The chunk goes before the run, as the input descends.
+    Before,
+}
+
+/// Least and greatest records of a chunk of the input which is in order already.
+struct Bounds {
+    min: Vec<u8>,
+    max: Vec<u8>,
+}
+
+/// Returns the bounds of `chunk` if its records are in ascending or descending order. Random
+/// input is told apart after a few records.
+fn bounds<F: Records>(records: &F, chunk: &[u8]) -> Option<Bounds> {
+    let (mut ascending, mut descending) = (true, true);
+    let mut first = None;
+    let mut last: Option<&[u8]> = None;
+    let mut rest = chunk;
+    while !rest.is_empty() {
+        // The last line of the input may lack its terminator.
+        let len = records.input_len(rest).unwrap_or(rest.len());
+        let (record, tail) = rest.split_at(len);
+        match last.map(|last| records.cmp_input(last, record)) {
+            Some(Ordering::Less) => descending = false,
+            Some(Ordering::Greater) => ascending = false,
+            Some(Ordering::Equal) => {}
+            None => first = Some(record),
+        }
+        if !ascending && !descending {
+            return None;
+        }
+        last = Some(record);
+        rest = tail;
+    }
+    let (first, last) = (first?, last?);
+    let (min, max) = if ascending {
+        (first, last)
+    } else {
+        (last, first)
+    };
+    Some(Bounds {
+        min: min.to_vec(),
+        max: max.to_vec(),
+    })
+}
+
+/// Joins the sorted chunks of the input into natural runs, which ascend or descend across the
+/// chunks, so presorted input makes fewer and longer runs.
+struct Joiner<'a, F> {
+    records: &'a F,
+    /// Whether equal records are dropped, so they can't be joined into a run which is output as
+    /// it is.
+    unique: bool,
+    /// Whether equal records keep their order, so a later one can't go before an earlier one.
+    stable: bool,
+    /// Direction and bounds of the last run, unless it's in no order.
+    run: Option<(Option<Join>, Bounds)>,
+}
+
+impl<'a, F: Records> Joiner<'a, F> {
+    fn new(records: &'a F, unique: bool) -> Self {
+        Joiner {
+            records,
+            unique,
+            stable: records.is_stable(),
+            run: None,
+        }
+    }
+
+    /// Tells how the next chunk of the given bounds continues the last run, if it does.
+    fn join(&mut self, bounds: Option<Bounds>) -> Option<Join> {
+        let bounds = match bounds {
+            Some(bounds) => bounds,
+            None => {
+                self.run = None;
+                return None;
+            }
+        };
+        let join = self.run.as_ref().and_then(|(direction, run)| {
+            let after = self.records.cmp_input(&bounds.min, &run.max);
+            let before = self.records.cmp_input(&bounds.max, &run.min);
+            if *direction != Some(Join::Before) && (after.is_gt() || after.is_eq() && !self.unique)
+            {
+                Some(Join::After)
+            } else if *direction != Some(Join::After)
+                && (before.is_lt() || before.is_eq() && !self.stable)
+            {
+                Some(Join::Before)
+            } else {
+                None
+            }
+        });
+        match (join, &mut self.run) {
+            (Some(Join::After), Some((direction, run))) => {
+                *direction = join;
+                run.max = bounds.max;
+            }
+            (Some(Join::Before), Some((direction, run))) => {
+                *direction = join;
+                run.min = bounds.min;
+            }
+            _ => self.run = Some((None, bounds)),
+        }
+        join
+    }
+}
+
+/// Sorts the input chunk by chunk on the current thread.
+pub(crate) fn generate_sequential<F: Records, R: Read>(
+    reader: &mut ChunkReader<F, R>,
+    unique: Option<Keep>,
+    writer: &mut RunWriter,
+) -> Result<(), SortError> {
+    let records = reader.records;
+    let mut joiner = Joiner::new(records, unique.is_some());
+    let mut cache = Vec::new();
+    while let Some(len) = reader.next_chunk(&mut cache)? {
+        let join = joiner.join(bounds(records, &cache[..len]));
+        writer.write_run(join, |out| {
+            records.sort_chunk(unique, &mut cache[..len], out)
+        })?;
+        cache.drain(..len);
+    }
+    Ok(())
+}
+
+/// Chunk of the input passed through the pipeline.
+#[derive(Default)]
+struct Chunk {
+    data: Vec<u8>,
+    sorted: Vec<u8>,
+    bounds: Option<Bounds>,
+}
+
+/// Sorts the input in a pipeline: the current thread reads chunks, `threads` threads sort them,
+/// and another thread writes them out in the input order, so the runs are the same as the
+/// sequential ones of the same chunk size.
+///
+/// There are `threads + 2` chunks in flight at most, each of them kept along with its sorted copy,
+/// so the reader's chunk size should be chosen accordingly to stay within the memory budget.
+pub(crate) fn generate_pipelined<F: Records, R: Read>(
+    reader: &mut ChunkReader<F, R>,
+    unique: Option<Keep>,
+    writer: &mut RunWriter,
+    threads: usize,
+) -> Result<(), SortError> {
+    let records = reader.records;
+    let (free_sender, free) = channel();
+    for _ in 0..threads + 2 {
+        let _ = free_sender.send(Chunk::default());
+    }
+    let (work_sender, work) = channel::<(u64, Chunk)>();
+    let work = Mutex::new(work);
+    let (done_sender, done) = channel();
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let (work, done_sender) = (&work, done_sender.clone());
+            scope.spawn(move || loop {
+                let next = work.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                let (seq, mut chunk) = match next {
+                    Ok(next) => next,
+                    Err(_) => break,
+                };
+                chunk.sorted.clear();
+                chunk.bounds = bounds(records, &chunk.data);
+                // Writing to a vector never fails.
+                let _ = records.sort_chunk(unique, &mut chunk.data, &mut chunk.sorted);
+                if done_sender.send((seq, chunk)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(done_sender);
+        // Once the writer stops, the free chunks channel is closed, which stops the reader.
+        let mut joiner = Joiner::new(records, unique.is_some());
+        let writing = scope.spawn(move || {
+            let mut pending = BTreeMap::new();
+            let mut next_seq = 0;
+            for (seq, chunk) in done {
+                pending.insert(seq, chunk);
+                while let Some(mut chunk) = pending.remove(&next_seq) {
+                    let join = joiner.join(chunk.bounds.take());
+                    writer.write_run(join, |out: &mut BufWriter<&mut File>| {
+                        out.write_all(&chunk.sorted)?;
+                        Ok(chunk.sorted.len() as u64)
+                    })?;
+                    next_seq += 1;
+                    let _ = free_sender.send(chunk);
+                }
+            }
+            Ok(())
+        });
+        let read = (|| {
+            let mut seq = 0;
+            let mut chunk = match free.recv() {
+                Ok(chunk) => chunk,
+                Err(_) => return Ok(()),
+            };
+            while let Some(len) = reader.next_chunk(&mut chunk.data)? {
+                let mut next = match free.recv() {
+                    Ok(next) => next,
+                    Err(_) => return Ok(()),
+                };
+                next.data.clear();
+                next.data.extend_from_slice(&chunk.data[len..]);
+                chunk.data.truncate(len);
+                if work_sender.send((seq, chunk)).is_err() {
+                    return Ok(());
+                }
+                seq += 1;
+                chunk = next;
+            }
+            Ok(())
+        })();
+        drop(work_sender);
+        let written = writing
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        read.and(written)
+    })
+}
+
+/// Generates runs by replacement selection, see [`RunStrategy::ReplacementSelection`]. The chunks
+/// of the reader are sorted first, which converts them into the records of the runs, and their
+/// records are pushed to a heap taking at most `capacity` bytes along with its bookkeeping.
+pub(crate) fn generate_replacement<F: Records, R: Read>(
+    reader: &mut ChunkReader<F, R>,
+    unique: Option<Keep>,
+    writer: &mut RunWriter,
+    capacity: usize,
+) -> Result<(), SortError> {
+    let records = reader.records;
+    let mut selection = Selection {
+        records,
+        unique,
+        arena: Vec::new(),
+        live: 0,
+        heap: Vec::new(),
+        capacity,
+        run: 0,
+        last: None,
+        spare: Vec::new(),
+    };
+    let mut chunk = Vec::new();
+    let mut sorted = Vec::new();
+    while let Some(len) = reader.next_chunk(&mut chunk)? {
+        sorted.clear();
+        // Writing to a vector never fails.
+        let _ = records.sort_chunk(unique, &mut chunk[..len], &mut sorted);
+        chunk.drain(..len);
+        let mut rest = &sorted[..];
+        while !rest.is_empty() {
+            let len = records
+                .record_len(rest)
+                .expect("sorted chunks consist of whole records; qed");
+            let (record, tail) = rest.split_at(len);
+            selection.push(record, writer)?;
+            rest = tail;
+        }
+    }
+    while !selection.heap.is_empty() {
+        selection.pop(writer)?;
+    }
+    selection.flush(writer)?;
+    writer.end_run();
+    Ok(())
+}
+
+/// State of the replacement selection. The records in the heap are stored one after another in an
+/// arena in the order they are pushed, and the holes left by the popped ones are squeezed out once
+/// they take a quarter of the capacity.
+struct Selection<'a, F> {
+    records: &'a F,
+    unique: Option<Keep>,
+    arena: Vec<u8>,
+    /// Length of the records in the arena, without the holes.
+    live: usize,
+    /// Binary heap of the records in the arena, with the least record of the earliest run at the
+    /// top.
+    heap: Vec<Entry>,
+    /// Most bytes the arena and the heap take.
+    capacity: usize,
+    /// Number of the run being written.
+    run: usize,
+    /// Last record of the run being written. It's written out once the next one is known not to be
+    /// its duplicate.
+    last: Option<Vec<u8>>,
+    /// Buffer of a written record to be reused.
+    spare: Vec<u8>,
+}
+
+/// Record in the heap of the replacement selection.
+#[derive(Clone, Copy)]
+struct Entry {
+    /// Number of the run the record goes to.
+    run: usize,
+    /// Position of the record in the arena, which keeps equal records in order.
+    start: usize,
+    len: usize,
+}
+
+impl<F: Records> Selection<'_, F> {
+    /// Pushes the next record of the input, popping records out of the heap to make room for it.
+    fn push(&mut self, record: &[u8], writer: &mut RunWriter) -> Result<(), SortError> {
+        let entry_size = mem::size_of::<Entry>();
+        while self.arena.len() + record.len() + (self.heap.len() + 1) * entry_size > self.capacity {
+            let holes = self.arena.len() - self.live;
+            if holes > self.capacity / 4 || self.heap.is_empty() && holes != 0 {
+                self.compact();
+            } else if !self.heap.is_empty() {
+                self.pop(writer)?;
+            } else {
+                // A record longer than the capacity is pushed on its own.
+                break;
+            }
+        }
+        // A record going before the last one can't be a part of the current run anymore.
+        let run = match &self.last {
+            Some(last) if self.records.cmp(record, last).is_lt() => self.run + 1,
+            _ => self.run,
+        };
+        self.heap.push(Entry {
+            run,
+            start: self.arena.len(),
+            len: record.len(),
+        });
+        self.arena.extend_from_slice(record);
+        self.live += record.len();
+        let (arena, records) = (&self.arena, self.records);
+        sift_up(&mut self.heap, |a, b| is_before(records, arena, a, b));
+        Ok(())
+    }
+
+    /// Moves the least record out of the heap to its run, starting the run if it's the next one.
+    fn pop(&mut self, writer: &mut RunWriter) -> Result<(), SortError> {
+        let entry = match self.heap.first() {
+            Some(&entry) => entry,
+            None => return Ok(()),
+        };
+        let last = self
+            .heap
+            .pop()
+            .expect("the heap has an entry at the top; qed");
+        if !self.heap.is_empty() {
+            self.heap[0] = last;
+            let (arena, records) = (&self.arena, self.records);
+            sift_down(&mut self.heap, 0, |a, b| is_before(records, arena, a, b));
+        }
+        let range = entry.start..entry.start + entry.len;
+        self.live -= entry.len;
+        if entry.run != self.run {
+            self.flush(writer)?;
+            writer.end_run();
+            self.run = entry.run;
+        }
+        let record = &self.arena[range];
+        match (&mut self.last, self.unique) {
+            (Some(last), Some(keep)) if self.records.is_duplicate(last, record) => {
+                self.records.merge_duplicate(keep, last, record);
+            }
+            _ => {
+                let mut buf = mem::take(&mut self.spare);
+                buf.clear();
+                buf.extend_from_slice(record);
+                if let Some(last) = self.last.replace(buf) {
+                    writer.append(&last)?;
+                    self.spare = last;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Squeezes the holes out of the arena, moving the records in it to the front.
+    fn compact(&mut self) {
+        // The records keep their order, and so does the heap.
+        self.heap.sort_unstable_by_key(|entry| entry.start);
+        let mut end = 0;
+        for entry in &mut self.heap {
+            self.arena
+                .copy_within(entry.start..entry.start + entry.len, end);
+            entry.start = end;
+            end += entry.len;
+        }
+        self.arena.truncate(end);
+        let (arena, records) = (&self.arena, self.records);
+        for i in (0..self.heap.len() / 2).rev() {
+            sift_down(&mut self.heap, i, |a, b| is_before(records, arena, a, b));
+        }
+    }
+
+    /// Writes out the last record of the run, if any.
+    fn flush(&mut self, writer: &mut RunWriter) -> Result<(), SortError> {
+        if let Some(last) = self.last.take() {
+            writer.append(&last)?;
+            self.spare = last;
+        }
+        Ok(())
+    }
+}
+
+/// Tells whether the record of entry `a` goes before the one of `b`: it belongs to an earlier run,
+/// or it's less, or it was pushed earlier.
+fn is_before<F: Records>(records: &F, arena: &[u8], a: &Entry, b: &Entry) -> bool {
+    let record = |e: &Entry| &arena[e.start..e.start + e.len];
+    a.run
+        .cmp(&b.run)
+        .then_with(|| records.cmp(record(a), record(b)))
+        .then(a.start.cmp(&b.start))
+        .is_lt()
+}
+
+/// Moves the last entry of the heap up until its parent goes before it.
+fn sift_up<B: Fn(&Entry, &Entry) -> bool>(heap: &mut [Entry], is_before: B) {
+    let mut i = heap.len() - 1;
+    while i > 0 {
+        let parent = (i - 1) / 2;
+        if !is_before(&heap[i], &heap[parent]) {
+            break;
+        }
+        heap.swap(i, parent);
+        i = parent;
+    }
+}
+
+/// Moves the `i`-th entry of the heap down until it goes before its children.
+fn sift_down<B: Fn(&Entry, &Entry) -> bool>(heap: &mut [Entry], mut i: usize, is_before: B) {
+    loop {
+        let mut least = i;
+        for child in [2 * i + 1, 2 * i + 2] {
+            if child < heap.len() && is_before(&heap[child], &heap[least]) {
+                least = child;
+            }
+        }
+        if least == i {
+            break;
+        }
+        heap.swap(i, least);
+        i = least;
+    }
+}
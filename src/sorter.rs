@@ -0,0 +1,665 @@
+use crate::check::check_order;
+use crate::compare::Compare;
+use crate::error::IoResultExt;
+use crate::histogram::Histogram;
+use crate::merge::{max_fan_in, FileSortHelper, Run};
+use crate::record::{CodecRecords, CollatedRecords, Counted, FormatRecords, Records};
+use crate::runs::{
+    generate_pipelined, generate_replacement, generate_sequential, ChunkReader, RunStrategy,
+    RunWriter,
+};
+use crate::temp::{default_temp_dir, TempFile};
+use crate::{
+    Collation, Disorder, Keep, KeySpec, Phase, RecordCodec, RecordFormat, SortError, MIN_CACHE_SIZE,
+};
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Stands for the input stream in errors.
+const STREAM_INPUT: &str = "<input>";
+/// Stands for the output stream in errors.
+const STREAM_OUTPUT: &str = "<output>";
+
+/// Memory budget used unless set explicitly.
+pub const DEFAULT_MEMORY: u64 = 64 * 1024 * 1024;
+
+/// Configures and runs sorting of a file.
+///
+/// ```no_run
+/// use big_file_sort::{RecordFormat, Sorter};
+///
+/// let report = Sorter::new("big_file.txt")
+///     .memory(512 * 1024 * 1024)
+///     .format(RecordFormat::Lines)
+///     .output("sorted.txt")
+///     .run()?;
+/// println!("Sorted {} bytes in {} runs", report.bytes, report.runs);
+/// # Ok::<(), big_file_sort::SortError>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct Sorter {
+    input: PathBuf,
+    output: Option<PathBuf>,
+    temp_dirs: Vec<PathBuf>,
+    memory: u64,
+    format: RecordFormat,
+    compare: Compare,
+    collation: Option<Collation>,
+    unique: bool,
+    keep: Keep,
+    count: bool,
+    run_strategy: RunStrategy,
+    parallelism: usize,
+}
+
+/// Summary of a finished sorting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SortReport {
+    /// Path of the sorted file, `None` when sorted into a stream.
+    pub output: Option<PathBuf>,
+    /// Size of the input in bytes.
+    pub bytes: u64,
+    /// Number of sorted runs the input was split into, zero when single bytes are sorted by
+    /// counting them.
+    pub runs: usize,
+    /// Number of merge passes over the data, including the final one.
+    pub merge_passes: usize,
+}
+
+impl Sorter {
+    /// Creates a sorter of the file at `input` with the default settings.
+    pub fn new<P: Into<PathBuf>>(input: P) -> Self {
+        Sorter {
+            input: input.into(),
+            output: None,
+            temp_dirs: Vec::new(),
+            memory: DEFAULT_MEMORY,
+            format: RecordFormat::default(),
+            compare: Compare::default(),
+            collation: None,
+            unique: false,
+            keep: Keep::default(),
+            count: false,
+            run_strategy: RunStrategy::default(),
+            parallelism: 1,
+        }
+    }
+
+    /// Sets the memory budget (cache size) in bytes. Must be at least [`MIN_CACHE_SIZE`].
+    pub fn memory(mut self, bytes: u64) -> Self {
+        self.memory = bytes;
+        self
+    }
+
+    /// Sets the directory for temporary files. Defaults to the `TMPDIR` environment variable, or to
+    /// the platform's temporary directory if it isn't set.
+    pub fn temp_dir<P: Into<PathBuf>>(self, dir: P) -> Self {
+        self.temp_dirs(Some(dir))
+    }
+
+    /// Sets several directories for temporary files, e.g. on different disks. Sorted runs are
+    /// spread over them round-robin, and read from all of them in parallel while merging.
+    pub fn temp_dirs<I>(mut self, dirs: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<PathBuf>,
+    {
+        self.temp_dirs = dirs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the path of the sorted file. Defaults to the input path with `out.txt` extension.
+    pub fn output<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.output = Some(path.into());
+        self
+    }
+
+    /// Sets how the input is split into records.
+    pub fn format(mut self, format: RecordFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the order of records. By default they are compared byte by byte.
+    pub fn comparator<F>(mut self, comparator: F) -> Self
+    where
+        F: Fn(&[u8], &[u8]) -> std::cmp::Ordering + Send + Sync + 'static,
+    {
+        self.compare.set_custom(Arc::new(comparator));
+        self
+    }
+
+    /// Adds a key to compare lines on. Lines are compared on the keys in the order they are added,
+    /// and lines with equal keys are compared as a whole.
+    pub fn key(mut self, key: KeySpec) -> Self {
+        self.compare.add_key(key);
+        self
+    }
+
+    /// Sets the byte separating the fields of keys. By default, a field consists of blanks followed
+    /// by non-blanks, so the blanks are a part of the field.
+    pub fn field_separator(mut self, separator: u8) -> Self {
+        self.compare.set_separator(Some(separator));
+        self
+    }
+
+    /// Sorts the records in descending order instead. Like with `sort -r`, keys with options of
+    /// their own, such as `2,2n`, keep their order, and only the other keys are reversed.
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.compare.set_reverse(reverse);
+        self
+    }
+
+    /// Sorts lines in the order of `collation`, e.g. names with accented letters, instead of byte
+    /// by byte. Lines of equal sort keys are compared byte by byte. Requires the
+    /// [`RecordFormat::Lines`] format, and can't be combined with keys or a comparator.
+    pub fn collation(mut self, collation: Collation) -> Self {
+        self.collation = Some(collation);
+        self
+    }
+
+    /// Keeps records with equal keys in the order of the input, both within and across the runs,
+    /// instead of comparing them as a whole. Defaults to `false`.
+    pub fn stable(mut self, stable: bool) -> Self {
+        self.compare.set_stable(stable);
+        self
+    }
+
+    /// Outputs only one of each group of equal records, i.e. of records with equal keys if there
+    /// are any. Duplicates are dropped from the runs as well, so they take less space. Defaults to
+    /// `false`.
+    pub fn unique(mut self, unique: bool) -> Self {
+        self.unique = unique;
+        self
+    }
+
+    /// Sets which of equal records the unique mode keeps, in the order of the input. Defaults to
+    /// [`Keep::First`].
+    pub fn keep(mut self, keep: Keep) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Outputs each group of equal lines once, after the number of lines in it, like
+    /// `sort | uniq -c` does. The lines are counted while the runs are generated, and the counts
+    /// of the runs are summed up while they are merged. Implies [`Sorter::unique`], and requires
+    /// the [`RecordFormat::Lines`] format. Defaults to `false`.
+    pub fn count(mut self, count: bool) -> Self {
+        self.count = count;
+        self
+    }
+
+    /// Returns which of equal records are kept in the unique mode, if it's on.
+    fn dedup(&self) -> Option<Keep> {
+        Some(self.keep).filter(|_| self.unique || self.count)
+    }
+
+    /// Tells whether equal records keep their order, which the unique mode needs to tell the first
+    /// of them from the last one.
+    fn is_stable(&self) -> bool {
+        self.compare.is_stable() || self.dedup().is_some()
+    }
+
+    /// Sets how the input is split into sorted runs. Defaults to [`RunStrategy::Chunks`].
+    ///
+    /// [`RunStrategy::ReplacementSelection`] makes fewer and longer runs, so they are merged in
+    /// fewer passes, but works on a single thread.
+    pub fn run_strategy(mut self, strategy: RunStrategy) -> Self {
+        self.run_strategy = strategy;
+        self
+    }
+
+    /// Sets the number of threads sorting the runs. Defaults to 1.
+    ///
+    /// With more than one thread, the input is read, sorted and written by different threads at the
+    /// same time, each of them working on its own part of the memory budget. The result is the
+    /// same as with a single thread.
+    pub fn parallelism(mut self, threads: usize) -> Self {
+        self.parallelism = threads;
+        self
+    }
+
+    fn validate(&self) -> Result<(), SortError> {
+        if self.memory < MIN_CACHE_SIZE {
+            return Err(SortError::Config(format!(
+                "cache size must be at least {} bytes, got {}",
+                MIN_CACHE_SIZE, self.memory
+            )));
+        }
+        if usize::try_from(self.memory).is_err() {
+            return Err(SortError::Limit(format!(
+                "cache size of {} bytes doesn't fit into the address space",
+                self.memory
+            )));
+        }
+        if max_fan_in(self.memory, self.temp_dirs.len()) < 2 {
+            return Err(SortError::Config(format!(
+                "cache size of {} bytes is too small to merge runs from {} temporary directories",
+                self.memory,
+                self.temp_dirs.len()
+            )));
+        }
+        self.format.validate().map_err(SortError::Config)?;
+        if self.collation.is_some() {
+            if self.format != RecordFormat::Lines {
+                return Err(SortError::Config(
+                    "collation requires the lines format".into(),
+                ));
+            }
+            if self.compare.is_custom() {
+                return Err(SortError::Config(
+                    "collation can't be combined with keys or a comparator".into(),
+                ));
+            }
+        }
+        if self.count && self.format != RecordFormat::Lines {
+            return Err(SortError::Config(
+                "counting requires the lines format".into(),
+            ));
+        }
+        if self.parallelism == 0 {
+            return Err(SortError::Config("parallelism must be at least 1".into()));
+        }
+        if self.run_strategy == RunStrategy::ReplacementSelection && self.parallelism > 1 {
+            return Err(SortError::Config(
+                "replacement selection works on a single thread".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the settings for sorting records of a codec, which are never counted.
+    fn validate_codec(&self) -> Result<(), SortError> {
+        if self.count {
+            return Err(SortError::Config(
+                "counting requires the lines format".into(),
+            ));
+        }
+        self.validate()
+    }
+
+    /// Returns the directories for temporary files.
+    fn resolved_temp_dirs(&self) -> Vec<PathBuf> {
+        if self.temp_dirs.is_empty() {
+            vec![default_temp_dir()]
+        } else {
+            self.temp_dirs.clone()
+        }
+    }
+
+    /// Returns the records of the configured format and order.
+    fn records(&self) -> FormatRecords {
+        let mut compare = self.compare.clone();
+        compare.set_stable(self.is_stable());
+        FormatRecords {
+            format: self.format,
+            compare,
+        }
+    }
+
+    /// Returns the records encoded by `codec`.
+    fn codec_records<C>(&self, codec: C) -> CodecRecords<C> {
+        CodecRecords {
+            codec,
+            stable: self.is_stable(),
+        }
+    }
+
+    /// Returns the collated lines, if a collation is set.
+    fn collated_records(&self) -> Option<CollatedRecords> {
+        self.collation.clone().map(|collation| CollatedRecords {
+            key_expansion: collation.key_expansion(),
+            collation,
+            reverse: self.compare.is_reverse(),
+            stable: self.is_stable(),
+        })
+    }
+
+    /// Sorts the input file. See [`sort_file`](crate::sort_file) for the algorithm.
+    pub fn run(&self) -> Result<SortReport, SortError> {
+        self.validate()?;
+        if self.counts_bytes() {
+            return self.run_histogram();
+        }
+        match self.collated_records() {
+            Some(records) => self.run_counted(&records),
+            None => self.run_counted(&self.records()),
+        }
+    }
+
+    /// Tells whether the records are single bytes in their natural or reverse order, which are
+    /// sorted by counting them instead of generating and merging runs.
+    fn counts_bytes(&self) -> bool {
+        self.format == RecordFormat::Bytes && !self.compare.is_custom()
+    }
+
+    /// Returns the path of the sorted file.
+    fn output_path(&self) -> PathBuf {
+        self.output
+            .clone()
+            .unwrap_or_else(|| self.input.with_extension("out.txt"))
+    }
+
+    /// Sorts the bytes of the input file by counting them, without temporary files.
+    fn run_histogram(&self) -> Result<SortReport, SortError> {
+        let path: &Path = &self.input;
+        let file = fs::File::open(path).during(Phase::RunGeneration, path)?;
+        let mut histogram = Histogram::new(self.memory as usize);
+        let bytes = histogram.count(io::BufReader::new(file), path)?;
+        // The output is only created now, so it may be the input itself.
+        let out_path = self.output_path();
+        let file_out = fs::File::create(&out_path).during(Phase::Merge, &out_path)?;
+        histogram.write(file_out, &out_path, self.compare.is_reverse(), self.unique)?;
+        Ok(SortReport {
+            output: Some(out_path),
+            bytes,
+            runs: 0,
+            merge_passes: 0,
+        })
+    }
+
+    /// Sorts the input file of `records`, counted if asked to.
+    fn run_counted<F: Records>(&self, records: &F) -> Result<SortReport, SortError> {
+        if self.count {
+            self.run_records(&Counted(records))
+        } else {
+            self.run_records(records)
+        }
+    }
+
+    /// Sorts the input file of records encoded by `codec`, in the order of the records. The
+    /// record format and the comparator of the sorter are ignored.
+    pub fn run_with_codec<C: RecordCodec>(&self, codec: C) -> Result<SortReport, SortError> {
+        self.validate_codec()?;
+        self.run_records(&self.codec_records(codec))
+    }
+
+    fn run_records<F: Records>(&self, records: &F) -> Result<SortReport, SortError> {
+        let path: &Path = &self.input;
+        let file = fs::File::open(path).during(Phase::RunGeneration, path)?;
+        let (mut tmp, runs, mut report) = self.generate_runs(records, file, path)?;
+        let out_path = self.output_path();
+        report.output = Some(out_path.clone());
+        // We have sorted the whole file. Return the temporary one, unless the run is stored in
+        // parts, e.g. of descending input, which are copied in order.
+        if runs.is_empty() || (runs.len() == 1 && !records.has_run_keys()) {
+            match runs.first() {
+                Some(run) if !run.next.is_empty() => {
+                    let mut file_out =
+                        fs::File::create(&out_path).during(Phase::Merge, &out_path)?;
+                    copy_run(&mut tmp[run.file], run, &mut file_out, &out_path)?;
+                }
+                run => {
+                    let file = run.map_or(0, |run| run.file);
+                    tmp.swap_remove(file).persist(&out_path)?;
+                }
+            }
+            return Ok(report);
+        }
+        // Here we should output to the initial file, but using another one for comparison.
+        let mut file_out = fs::File::create(&out_path).during(Phase::Merge, &out_path)?;
+        report.merge_passes = self.merge_runs(records, tmp, runs, &mut file_out, &out_path)?;
+        Ok(report)
+    }
+
+    /// Sorts everything read from `input` and writes the result to `output`, ignoring the input and
+    /// output paths of the sorter. Sorted runs are still spilled to temporary files, but the final
+    /// merge goes straight to `output`.
+    ///
+    /// ```no_run
+    /// use big_file_sort::{RecordFormat, Sorter};
+    /// use std::io;
+    ///
+    /// Sorter::default()
+    ///     .format(RecordFormat::Lines)
+    ///     .run_stream(io::stdin().lock(), io::stdout().lock())?;
+    /// # Ok::<(), big_file_sort::SortError>(())
+    /// ```
+    pub fn run_stream<R: Read, W: Write>(
+        &self,
+        input: R,
+        output: W,
+    ) -> Result<SortReport, SortError> {
+        self.validate()?;
+        if self.counts_bytes() {
+            let mut histogram = Histogram::new(self.memory as usize);
+            let bytes = histogram.count(input, Path::new(STREAM_INPUT))?;
+            histogram.write(
+                output,
+                Path::new(STREAM_OUTPUT),
+                self.compare.is_reverse(),
+                self.unique,
+            )?;
+            return Ok(SortReport {
+                output: None,
+                bytes,
+                runs: 0,
+                merge_passes: 0,
+            });
+        }
+        match self.collated_records() {
+            Some(records) => self.run_stream_counted(&records, input, output),
+            None => self.run_stream_counted(&self.records(), input, output),
+        }
+    }
+
+    /// Sorts the stream of `records`, counted if asked to.
+    fn run_stream_counted<F: Records, R: Read, W: Write>(
+        &self,
+        records: &F,
+        input: R,
+        output: W,
+    ) -> Result<SortReport, SortError> {
+        if self.count {
+            self.run_stream_records(&Counted(records), input, output)
+        } else {
+            self.run_stream_records(records, input, output)
+        }
+    }
+
+    /// Sorts the records encoded by `codec` read from `input`, and writes them to `output`. Works
+    /// like [`Sorter::run_stream`], but ignores the record format and the comparator.
+    pub fn run_stream_with_codec<C: RecordCodec, R: Read, W: Write>(
+        &self,
+        codec: C,
+        input: R,
+        output: W,
+    ) -> Result<SortReport, SortError> {
+        self.validate_codec()?;
+        self.run_stream_records(&self.codec_records(codec), input, output)
+    }
+
+    fn run_stream_records<F: Records, R: Read, W: Write>(
+        &self,
+        records: &F,
+        input: R,
+        mut output: W,
+    ) -> Result<SortReport, SortError> {
+        let out_path = Path::new(STREAM_OUTPUT);
+        let (mut tmp, runs, mut report) =
+            self.generate_runs(records, input, Path::new(STREAM_INPUT))?;
+        // There is nothing to merge - copy the only run, if any.
+        if runs.is_empty() || (runs.len() == 1 && !records.has_run_keys()) {
+            if let Some(run) = runs.first() {
+                copy_run(&mut tmp[run.file], run, &mut output, out_path)?;
+            }
+            output.flush().during(Phase::Merge, out_path)?;
+            return Ok(report);
+        }
+        report.merge_passes = self.merge_runs(records, tmp, runs, &mut output, out_path)?;
+        Ok(report)
+    }
+
+    /// Checks whether the input file is sorted in the configured order, like `sort -c` does,
+    /// without writing anything. Returns the first record out of order, if any. In the unique
+    /// mode, a record equal to the previous one is out of order as well.
+    ///
+    /// ```no_run
+    /// use big_file_sort::{RecordFormat, Sorter};
+    ///
+    /// let sorter = Sorter::new("sorted.txt").format(RecordFormat::Lines);
+    /// if let Some(disorder) = sorter.check()? {
+    ///     println!("line {} at byte {} is out of order", disorder.line, disorder.offset);
+    /// }
+    /// # Ok::<(), big_file_sort::SortError>(())
+    /// ```
+    pub fn check(&self) -> Result<Option<Disorder>, SortError> {
+        let path: &Path = &self.input;
+        let file = fs::File::open(path).during(Phase::Check, path)?;
+        self.check_input(file, path)
+    }
+
+    /// Checks whether everything read from `input` is sorted, ignoring the input path of the
+    /// sorter. Works like [`Sorter::check`].
+    pub fn check_stream<R: Read>(&self, input: R) -> Result<Option<Disorder>, SortError> {
+        self.check_input(input, Path::new(STREAM_INPUT))
+    }
+
+    fn check_input<R: Read>(
+        &self,
+        mut input: R,
+        input_path: &Path,
+    ) -> Result<Option<Disorder>, SortError> {
+        if self.count {
+            return Err(SortError::Config(
+                "counting can't be combined with checking".into(),
+            ));
+        }
+        self.validate()?;
+        let cache_size = self.memory as usize;
+        let unique = self.dedup().is_some();
+        match self.collated_records() {
+            Some(records) => {
+                let reader = ChunkReader::new(&records, &mut input, input_path, cache_size);
+                check_order(&mut reader.phase(Phase::Check), unique)
+            }
+            None => {
+                let records = self.records();
+                let reader = ChunkReader::new(&records, &mut input, input_path, cache_size);
+                check_order(&mut reader.phase(Phase::Check), unique)
+            }
+        }
+    }
+
+    /// Splits the input into sorted runs stored in temporary files.
+    fn generate_runs<F: Records, R: Read>(
+        &self,
+        records: &F,
+        mut input: R,
+        input_path: &Path,
+    ) -> Result<(Vec<TempFile>, Vec<Run>, SortReport), SortError> {
+        let cache_size = self.memory as usize;
+        // Prepare temporary files.
+        let mut tmp = create_temp_files(&self.resolved_temp_dirs(), Phase::RunGeneration)?;
+        let mut writer = RunWriter::new(&mut tmp);
+        let bytes = if self.run_strategy == RunStrategy::ReplacementSelection {
+            // The chunk being read and the same chunk sorted take an eighth of the cache, and the
+            // heap the rest.
+            let chunk_size = cache_size / 16;
+            let mut reader = ChunkReader::new(records, &mut input, input_path, chunk_size);
+            let capacity = cache_size - 2 * chunk_size;
+            generate_replacement(&mut reader, self.dedup(), &mut writer, capacity)?;
+            reader.bytes_read()
+        } else if self.parallelism > 1 {
+            // Chunks being read, sorted and written share the cache, each of them along with its
+            // sorted copy.
+            let chunk_size = cache_size / (2 * (self.parallelism + 2));
+            let mut reader = ChunkReader::new(records, &mut input, input_path, chunk_size);
+            generate_pipelined(&mut reader, self.dedup(), &mut writer, self.parallelism)?;
+            reader.bytes_read()
+        } else {
+            let mut reader = ChunkReader::new(records, &mut input, input_path, cache_size);
+            generate_sequential(&mut reader, self.dedup(), &mut writer)?;
+            reader.bytes_read()
+        };
+        let runs = writer.finish()?;
+        let report = SortReport {
+            output: None,
+            bytes,
+            runs: runs.len(),
+            merge_passes: 0,
+        };
+        Ok((tmp, runs, report))
+    }
+
+    /// Merges the runs into `output`. Returns the number of merge passes.
+    fn merge_runs<F: Records, W: Write>(
+        &self,
+        records: &F,
+        mut tmp: Vec<TempFile>,
+        mut runs: Vec<Run>,
+        output: &mut W,
+        output_path: &Path,
+    ) -> Result<usize, SortError> {
+        let cache_size = self.memory;
+        let mut passes = 1;
+        // Merge groups of runs until all of them can be merged at once.
+        let fan_in = max_fan_in(cache_size, tmp.len()) as usize;
+        while runs.len() > fan_in {
+            let mut merged = create_temp_files(&self.resolved_temp_dirs(), Phase::Merge)?;
+            let mut merged_len = vec![0; merged.len()];
+            let mut merged_runs = Vec::with_capacity(runs.len() / fan_in + 1);
+            for group in runs.chunks(fan_in) {
+                let i = merged_runs.len() % merged.len();
+                let out = &mut merged[i];
+                let run_len = FileSortHelper::new(
+                    records,
+                    cache_size,
+                    group,
+                    &mut tmp,
+                    &mut out.file,
+                    &out.path,
+                )
+                .unique(self.dedup())
+                .merge()?;
+                merged_runs.push(Run::new(i, merged_len[i]..merged_len[i] + run_len));
+                merged_len[i] += run_len;
+            }
+            runs = merged_runs;
+            tmp = merged;
+            passes += 1;
+        }
+        // Sort input file using the temporary ones.
+        FileSortHelper::new(records, cache_size, &runs, &mut tmp, output, output_path)
+            .unique(self.dedup())
+            .output(true)
+            .merge()?;
+        Ok(passes)
+    }
+}
+
+impl Default for Sorter {
+    /// Creates a sorter with the default settings and no input path, to be used with
+    /// [`Sorter::run_stream`].
+    fn default() -> Self {
+        Sorter::new(PathBuf::new())
+    }
+}
+
+/// Copies the parts of `run` stored in `tmp` to `output` in order.
+fn copy_run<W: Write>(
+    tmp: &mut TempFile,
+    run: &Run,
+    output: &mut W,
+    output_path: &Path,
+) -> Result<(), SortError> {
+    for part in run.parts() {
+        tmp.file
+            .seek(SeekFrom::Start(part.start))
+            .during(Phase::Merge, &tmp.path)?;
+        io::copy(&mut (&mut tmp.file).take(part.end - part.start), output)
+            .during(Phase::Merge, output_path)?;
+    }
+    Ok(())
+}
+
+/// Creates a temporary file in each of the directories.
+fn create_temp_files(dirs: &[PathBuf], phase: Phase) -> Result<Vec<TempFile>, SortError> {
+    dirs.iter()
+        .map(|dir| TempFile::create(dir, phase))
+        .collect()
+}
@@ -0,0 +1,114 @@
+use crate::error::IoResultExt;
+use crate::{Phase, SortError};
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Prefix of the temporary file names.
+const PREFIX: &str = "big-file-sort-";
+/// How many names are tried before giving up on creating a temporary file.
+const MAX_ATTEMPTS: usize = 16;
+
+/// Returns the directory for temporary files used when none is configured. It is taken from the
+/// `TMPDIR` environment variable, falling back to the platform default.
+pub(crate) fn default_temp_dir() -> PathBuf {
+    std::env::temp_dir()
+}
+
+/// Generates a hard to guess file name.
+fn random_name() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    // `RandomState` is seeded randomly, which is enough for names not to be predictable.
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u32(std::process::id());
+    if let Ok(time) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        hasher.write_u128(time.as_nanos());
+    }
+    format!("{}{:016x}.tmp", PREFIX, hasher.finish())
+}
+
+/// A temporary file which is automatically removed once dropped.
+pub(crate) struct TempFile {
+    pub(crate) path: PathBuf,
+    pub(crate) file: File,
+    persisted: bool,
+}
+
+impl TempFile {
+    /// Creates a new file with a unique name in `dir`. The file is only accessible by the current
+    /// user, and an existing file is never reused.
+    pub(crate) fn create(dir: &Path, phase: Phase) -> Result<Self, SortError> {
+        for _ in 0..MAX_ATTEMPTS {
+            let path = dir.join(random_name());
+            let mut options = OpenOptions::new();
+            options.read(true).write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            match options.open(&path) {
+                Ok(file) => {
+                    return Ok(TempFile {
+                        path,
+                        file,
+                        persisted: false,
+                    })
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e).during(phase, &path),
+            }
+        }
+        Err(Error::new(
+            ErrorKind::AlreadyExists,
+            "failed to find an unused temporary file name",
+        ))
+        .during(phase, dir)
+    }
+
+    /// Moves the file to `to`, so it is no longer removed. Falls back to copying when the
+    /// destination is on another file system.
+    pub(crate) fn persist(mut self, to: &Path) -> Result<(), SortError> {
+        if fs::rename(&self.path, to).is_ok() {
+            self.persisted = true;
+            return Ok(());
+        }
+        fs::copy(&self.path, to).during(Phase::Rename, to)?;
+        Ok(())
+    }
+}
+
+/// Automatically drop the temporary file.
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_create_unique_files_and_remove_them() {
+        let dir = default_temp_dir();
+        let a = TempFile::create(&dir, Phase::RunGeneration).unwrap();
+        let b = TempFile::create(&dir, Phase::RunGeneration).unwrap();
+        assert_ne!(a.path, b.path);
+        assert!(a.path.starts_with(&dir));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = a.file.metadata().unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let path = a.path.clone();
+        drop(a);
+        assert!(!path.exists());
+    }
+}
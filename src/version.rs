@@ -0,0 +1,143 @@
+//! Comparison of version numbers and file names with numbers in them, as done by `sort -V`.
+
+use std::cmp::Ordering;
+
+/// Compares texts as versions: runs of digits are compared as numbers, and the rest byte by byte,
+/// with letters going before other characters and `~` going before everything, even the end of
+/// the text. File suffixes such as `.tar.gz` are only compared when the rest is equal, and names
+/// starting with `.` go first.
+pub(crate) fn cmp_version(a: &[u8], b: &[u8]) -> Ordering {
+    match (a, b) {
+        ([], []) => return Ordering::Equal,
+        ([], _) => return Ordering::Less,
+        (_, []) => return Ordering::Greater,
+        ([b'.', ..], [b'.', ..]) => {
+            // `.` goes first, then `..`, then the other hidden files.
+            for special in [&b"."[..], b".."] {
+                match (a == special, b == special) {
+                    (true, true) => return Ordering::Equal,
+                    (true, false) => return Ordering::Less,
+                    (false, true) => return Ordering::Greater,
+                    (false, false) => {}
+                }
+            }
+        }
+        ([b'.', ..], _) => return Ordering::Less,
+        (_, [b'.', ..]) => return Ordering::Greater,
+        _ => {}
+    }
+    let (a_prefix, b_prefix) = (prefix_len(a), prefix_len(b));
+    let ord = cmp_parts(&a[..a_prefix], &b[..b_prefix]);
+    if ord.is_ne() || (a_prefix == a.len() && b_prefix == b.len()) {
+        return ord;
+    }
+    cmp_parts(a, b)
+}
+
+/// Returns the length of the text without its file suffix, i.e. without the trailing parts made of
+/// a dot followed by a letter or `~`, and then by alphanumeric characters or `~`.
+fn prefix_len(text: &[u8]) -> usize {
+    let is_suffix_start = |b: u8| b.is_ascii_alphabetic() || b == b'~';
+    let is_suffix = |b: u8| b.is_ascii_alphanumeric() || b == b'~';
+    let mut i = 0;
+    loop {
+        let prefix_len = i;
+        while i + 1 < text.len() && text[i] == b'.' && is_suffix_start(text[i + 1]) {
+            i += 2;
+            while i < text.len() && is_suffix(text[i]) {
+                i += 1;
+            }
+        }
+        if i == text.len() {
+            return prefix_len;
+        }
+        i += 1;
+    }
+}
+
+/// Weight of a non-digit character, the end of the text weighs 0.
+fn order(b: u8) -> i32 {
+    match b {
+        b'0'..=b'9' => 0,
+        b'~' => -1,
+        _ if b.is_ascii_alphabetic() => i32::from(b),
+        _ => i32::from(b) + 256,
+    }
+}
+
+/// Compares alternating runs of non-digits and digits.
+fn cmp_parts(a: &[u8], b: &[u8]) -> Ordering {
+    let (mut i, mut j) = (0, 0);
+    let is_digit = |text: &[u8], pos: usize| text.get(pos).is_some_and(u8::is_ascii_digit);
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !is_digit(a, i)) || (j < b.len() && !is_digit(b, j)) {
+            let x = a.get(i).map_or(0, |&c| order(c));
+            let y = b.get(j).map_or(0, |&c| order(c));
+            if x != y {
+                return x.cmp(&y);
+            }
+            i += 1;
+            j += 1;
+        }
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+        let mut first_diff = Ordering::Equal;
+        while is_digit(a, i) && is_digit(b, j) {
+            first_diff = first_diff.then(a[i].cmp(&b[j]));
+            i += 1;
+            j += 1;
+        }
+        // The longer number is the greater one.
+        if is_digit(a, i) {
+            return Ordering::Greater;
+        }
+        if is_digit(b, j) {
+            return Ordering::Less;
+        }
+        if first_diff.is_ne() {
+            return first_diff;
+        }
+    }
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_compare_versions() {
+        let sorted = [
+            "",
+            ".",
+            "..",
+            ".hidden",
+            "1.0~rc1",
+            "1.0",
+            "1.0.1",
+            "1.2",
+            "1.10",
+            "1.10.2",
+            "a",
+            "img2.png",
+            "img12.png",
+            "v1.9.0",
+            "v1.10.2",
+            "v1.10.2a",
+        ];
+        for w in sorted.windows(2) {
+            assert_eq!(
+                cmp_version(w[0].as_bytes(), w[1].as_bytes()),
+                Ordering::Less,
+                "{:?}",
+                w
+            );
+        }
+        assert_eq!(cmp_version(b"file01.txt", b"file1.txt"), Ordering::Equal);
+        assert_eq!(cmp_version(b"a.tar.gz", b"a.zip"), Ordering::Less);
+    }
+}